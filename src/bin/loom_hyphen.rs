@@ -0,0 +1,183 @@
+extern crate loom;
+extern crate futures;
+
+use std::env;
+use std::fs;
+use std::process;
+use std::path::Path;
+use std::collections::BTreeSet;
+use loom::hyphenation::{Hyphenator, Hyphens, Hyphen};
+use loom::patterns::Patterns;
+use loom::source::{BlockBody, Body, Item};
+use loom::parser;
+use futures::Future;
+
+const USAGE: &str = "\
+usage: loom-hyphen <command> ...
+
+  build <list> <out>            build a dictionary from `hy|phen|ated` lines (`=` is preferred)
+  merge <dict> <list> <out>     add the words of a list to a dictionary
+  lookup <dict> <word>...       show the break points of words
+  dump <dict>                   write a dictionary as a list
+  check <dict> <file.yarn>      list the words without hyphenation data
+
+<dict> is a dictionary. `lookup` and `check` also take a `hyph-*.pat` pattern file,
+which has no words to merge into or dump.";
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(1)
+}
+
+fn save(h: Hyphenator, path: &str) {
+    h.save_async(path.into()).wait().unwrap_or_else(|e| {
+        eprintln!("can't write {}: {}", path, e);
+        process::exit(1)
+    })
+}
+
+fn add(h: &mut Hyphenator, path: &str) {
+    h.add_hyphenlist(Path::new(path)).unwrap_or_else(|e| {
+        eprintln!("can't read {}: {}", path, e);
+        process::exit(1)
+    })
+}
+
+fn is_patterns(path: &str) -> bool {
+    path.contains(".pat")
+}
+
+// a dictionary with words, not patterns
+fn open_words(path: &str) -> Hyphenator {
+    if is_patterns(path) {
+        eprintln!("{} is a pattern file, not a dictionary", path);
+        process::exit(1)
+    }
+    open(path)
+}
+
+fn open(path: &str) -> Hyphenator {
+    let data = fs::read(path).unwrap_or_else(|e| {
+        eprintln!("can't read {}: {}", path, e);
+        process::exit(1)
+    });
+
+    if is_patterns(path) {
+        let mut h = Hyphenator::empty();
+        h.set_patterns(Patterns::parse(&String::from_utf8_lossy(&data)));
+        h
+    } else {
+        Hyphenator::load(data).unwrap_or_else(|e| {
+            eprintln!("{} is not a dictionary: {:?}", path, e);
+            process::exit(1)
+        })
+    }
+}
+
+// `hy|phen|ated`, the format `add_hyphenlist` reads
+fn mark(word: &str, hyphens: &Hyphens) -> String {
+    let points: Vec<Hyphen> = hyphens.iter().collect();
+
+    let mut out = String::new();
+    for (n, c) in word.chars().enumerate() {
+        if let Some(p) = points.iter().find(|p| p.pos() == n) {
+            out.push(if p.weight() >= Hyphen::PREFERRED { '=' } else { '|' });
+        }
+        out.push(c);
+    }
+    out
+}
+
+fn words(body: &BlockBody, out: &mut BTreeSet<String>) {
+    fn items(list: &[Item], out: &mut BTreeSet<String>) {
+        for i in list {
+            match *i {
+                Item::Word(ref w) => {
+                    out.insert(w.to_string());
+                },
+                Item::Group(ref g) => items(&g.content, out),
+                _ => {}
+            }
+        }
+    }
+
+    for p in body.parameters.iter() {
+        items(&p.args, out);
+        words(&p.value, out);
+    }
+    for c in body.childs.iter() {
+        match *c {
+            Body::Leaf(ref i) => items(i, out),
+            Body::List(ref list) => {
+                for i in list.iter() {
+                    items(i, out);
+                }
+            },
+            Body::Block(ref b) => {
+                items(&b.argument, out);
+                words(&b.body, out);
+            },
+            Body::Placeholder(_) => {}
+        }
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let command = args.get(0).map(|s| s.as_str()).unwrap_or("");
+
+    match (command, args.len()) {
+        ("build", 3) => {
+            let mut h = Hyphenator::empty();
+            add(&mut h, &args[1]);
+            save(h, &args[2]);
+        },
+        ("merge", 4) => {
+            let mut h = open_words(&args[1]);
+            add(&mut h, &args[2]);
+            save(h, &args[3]);
+        },
+        ("lookup", n) if n > 2 => {
+            let h = open(&args[1]);
+            for word in args[2 ..].iter() {
+                match h.get(word) {
+                    Some(hyphens) => println!("{}", mark(word, &hyphens)),
+                    None => println!("{} (unknown)", word)
+                }
+            }
+        },
+        ("dump", 2) => {
+            for (word, hyphens) in open_words(&args[1]).entries() {
+                println!("{}", mark(&word, &hyphens));
+            }
+        },
+        ("check", 3) => {
+            let h = open(&args[1]);
+            let file = &args[2];
+            let text = fs::read_to_string(file).unwrap_or_else(|e| {
+                eprintln!("can't read {}: {}", file, e);
+                process::exit(1)
+            });
+            let body = match parser::block_body(&text, 0) {
+                Ok((_, body)) => body,
+                Err(_) => {
+                    eprintln!("can't parse {}", file);
+                    process::exit(1)
+                }
+            };
+
+            let mut found = BTreeSet::new();
+            words(&body, &mut found);
+
+            // short words are not hyphenated anyway
+            let missing: Vec<&String> = found.iter()
+                .filter(|w| w.chars().count() > 4 && h.get(w).is_none())
+                .collect();
+            for w in missing.iter() {
+                println!("{}", w);
+            }
+            eprintln!("{} of {} words without hyphenation data", missing.len(), found.len());
+        },
+        _ => usage()
+    }
+}
@@ -35,6 +35,14 @@ fn main() {
         let mut output = HtmlOutput::new(str::from_utf8(&style).unwrap());
         use html::*;
         
+        // first pass: number the anchors
+        let marks = {
+            let mut sink = ::std::io::sink();
+            let mut w = HtmlWriter::new(&mut output, &mut sink);
+            yarn.layout(&mut w);
+            w.finish()
+        };
+        
         let mut file = OpenOptions::new()
         .write(true)
         .create(true)
@@ -43,7 +51,7 @@ fn main() {
         .expect("could not open/create HTML file for writing");
         
         let mut w = HtmlWriter::new(&mut output, &mut file);
-        yarn.layout(&mut w);
+        yarn.layout_with(&marks, &mut w);
         
         w.finish();
     });
@@ -37,10 +37,16 @@ fn main() {
             .map(|(yarn, output)| (yarn, output, name))
     })
     .map(|(yarn, output, name)| {
+        // find out where the marks end up
+        let marks = yarn.marks(&output, 800., 800.);
+        
         let mut w = GenericWriter::new(&output);
-        yarn.layout(&mut w);
+        yarn.layout_with(&marks, &mut w);
         
         let layout = ColumnLayout::new(w.finish(), 800., 800.);
+        for d in layout.diagnostics() {
+            warn!(Log::root(), "{}", d);
+        }
         for (i, column) in layout.columns().enumerate() {
             println!("column {}: {:?}", i, column);
             let mut surface = output.surface(T2(900., 900.));
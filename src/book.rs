@@ -4,6 +4,7 @@ use output::Output;
 use layout::*;
 use tuple::T2;
 use std::fmt::Debug;
+use wheel::Log;
 
 pub struct PageMetrics {
     pub width:          Length,  // overall width of the final page
@@ -44,10 +45,18 @@ pub struct Pages<O: Output> {
 
 impl Book {
     fn render<O>(&self, output: &O) -> Pages<O> where O: Output + Debug {
+        // find out on which pages the marks end up
+        let marks = self.content.marks(output, self.page.text_width(), self.page.text_height());
+        
         let mut w = GenericWriter::new(output);
-        self.content.layout(&mut w);
+        self.content.layout_with(&marks, &mut w);
         
-        let layout = ColumnLayout::new(w.finish(), self.page.text_width(), self.page.text_height());
+        let style = output.style_or_default("default");
+        let layout = ColumnLayout::with_params(w.finish(), self.page.text_width(), self.page.text_height(),
+            style.breaks, style.columns);
+        for d in layout.diagnostics() {
+            warn!(Log::root(), "{}", d);
+        }
         
         let mut page_nr = 1;
         let mut surfaces = Vec::new();
@@ -61,14 +70,15 @@ impl Book {
                 for (dx, e) in line {
                     match e {
                         Item::Word(word) => O::draw_word(&mut surface, T2(dx, dy) + offset, word),
-                        Item::Anchor(data) => marginnotes.push((dy, data))
+                        Item::Anchor(data) => marginnotes.push((dy, data)),
+                        Item::Mark(_) => {}
                     }
                 }
             }
             
             // margin
             for (y, data) in marginnotes {
-                let layout = ParagraphLayout::new(data, self.page.margin_outer);
+                let layout = ParagraphLayout::with_params(data, self.page.margin_outer, style.breaks);
                 for (dy, line) in layout.lines() {
                     let offset = T2(self.page.width - self.page.margin_outer, self.page.margin_top + y);
                     for (dx, e) in line {
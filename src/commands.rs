@@ -5,6 +5,9 @@ use std::boxed::FnBox;
 use futures::Future;
 use futures::future::{ok, join_all};
 use super::{LoomError};
+use language::Language;
+use hyphenation::HyphenatorFiles;
+use shared::Shared;
 use istring::IString;
 
 pub fn register(env: &mut LocalEnv) {
@@ -15,6 +18,9 @@ pub fn register(env: &mut LocalEnv) {
     env.add_command("use",          cmd_use);
     env.add_command("symbol",       cmd_symbol);
     env.add_command("aside",        cmd_aside);
+    env.add_command("toc",          cmd_toc);
+    env.add_command("language",     cmd_language);
+    env.add_command("hyphenmin",    cmd_hyphenmin);
 }
 
 #[allow(unused_macros)]
@@ -88,19 +94,22 @@ fn cmd_group(io: &Io, _env: &GraphChain, args: Vec<IString>)
 fn cmd_hyphens(io: &Io, _env: &GraphChain, args: Vec<IString>)
  -> CommandResult
 {
-    use hyphenation::Hyphenator;
-
-    if args.len() != 1 {
-        return Err(LoomError::MissingArg("filename"))
-    }
-    let f = io.config(|conf| open_read(&conf.data_dir, &args[0]))
-    .and_then(|data| {
-        Hyphenator::load(data.to_vec())
-        .map_err(|e| LoomError::Hyphenator(e))
-        .and_then(|h| 
-            Ok(complete(|_env: &GraphChain, local: &mut LocalEnv| local.set_hyphenator(h)))
-        )
-    });
+    // a dictionary, optionally followed by a pattern file, or only a pattern file
+    let files = match args.len() {
+        1 if args[0].ends_with(".pat") => HyphenatorFiles {
+            dictionary: None,
+            patterns:   Some(args[0].clone())
+        },
+        1 | 2 => HyphenatorFiles {
+            dictionary: Some(args[0].clone()),
+            patterns:   args.get(1).cloned()
+        },
+        _ => return Err(LoomError::MissingArg("filename"))
+    };
+    let f = io.hyphenator(&files)
+    .and_then(move |h| 
+        Ok(complete(|_env: &GraphChain, local: &mut LocalEnv| local.set_hyphenator(files, h)))
+    );
     Ok(box f)
 }
 
@@ -121,7 +130,7 @@ fn cmd_load(io: &Io, env: &GraphChain, args: Vec<IString>)
         io.config(|conf| open_read(&conf.yarn_dir, &filename))
         .and_then(move |data| {
             let string = String::from_utf8(data.to_vec()).unwrap();
-            Module::parse(io, env, string)
+            Module::parse(io, env, string, Some(filename.into()))
         })
         .map(|module| (module, name))
     })
@@ -230,3 +239,49 @@ fn cmd_aside(io: &Io, _: &GraphChain, args: Vec<IString>) -> CommandResult
         local.add_target(name, node.into());
     })))
 }
+
+/// `!hyphenmin left right`
+/// hyphenation leaves at least `left` chars on the first line and `right` on the next
+fn cmd_hyphenmin(_io: &Io, _: &GraphChain, args: Vec<IString>) -> CommandResult
+{
+    if args.len() != 2 {
+        return Err(LoomError::MissingArg("left right"));
+    }
+    let left = args[0].parse().map_err(|_| LoomError::InvalidArg("left"))?;
+    let right = args[1].parse().map_err(|_| LoomError::InvalidArg("right"))?;
+
+    Ok(box ok(complete(move |_env: &GraphChain, local: &mut LocalEnv| {
+        local.set_hyphenmin(left, right)
+    })))
+}
+
+/// `!toc [level]`
+/// blocks using this environment are listed in the table of contents
+fn cmd_toc(_io: &Io, _: &GraphChain, args: Vec<IString>) -> CommandResult
+{
+    let level = match args.get(0) {
+        Some(arg) => match arg.parse() {
+            Ok(level) => level,
+            Err(_) => return Err(LoomError::InvalidArg("level"))
+        },
+        None => 1
+    };
+
+    Ok(box ok(complete(move |_env: &GraphChain, local: &mut LocalEnv| {
+        local.set_toc(level)
+    })))
+}
+
+fn cmd_language(_io: &Io, _: &GraphChain, args: Vec<IString>) -> CommandResult
+{
+    cmd_args!{args; name};
+
+    let language = match Language::by_name(&name) {
+        Some(language) => Shared::new(language),
+        None => return Err(LoomError::InvalidArg("language"))
+    };
+
+    Ok(box ok(complete(move |_env: &GraphChain, local: &mut LocalEnv| {
+        local.set_language(language)
+    })))
+}
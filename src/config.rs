@@ -1,3 +1,4 @@
+use std::path::PathBuf;
 use wheel::Directory;
 use serde_json;
 use futures::{Future, future};
@@ -16,7 +17,10 @@ pub struct Config {
     pub style_dir:  Directory,
     pub data_dir:   Directory,
     pub font_dir:   Directory,
-    pub yarn_dir:   Directory
+    pub yarn_dir:   Directory,
+    
+    /// where `data_dir` is, for files that are mapped into memory
+    pub data_path:  PathBuf
 }
 
 #[derive(Debug)]
@@ -37,15 +41,17 @@ impl Config {
                 let fonts = Directory::open(&raw.font_dir);
                 let data = Directory::open(&raw.data_dir);
                 let yarn = Directory::open(&raw.yarn_dir);
+                let data_path = PathBuf::from(&raw.data_dir);
             
                 styles.join4(fonts, data, yarn)
                 .map_err(|e| ParseError::IoOpen(e))
-            })
-            .map(|(styles, fonts, data, yarn)| Config {
-                style_dir:  styles,
-                font_dir:   fonts,
-                data_dir:   data,
-                yarn_dir:   yarn
+                .map(move |(styles, fonts, data, yarn)| Config {
+                    style_dir:  styles,
+                    font_dir:   fonts,
+                    data_dir:   data,
+                    yarn_dir:   yarn,
+                    data_path:  data_path
+                })
             })
         })
     }
@@ -1,13 +1,18 @@
 use std::iter::Iterator;
 use std::ops::Deref;
-use std::cell::RefCell;
-use std::rc::Rc;
+use shared::{Shared, Weak, Lock, ReadGuard, MaybeSync};
+use std::collections::{HashSet, HashMap};
 use layout::{Atom, Writer};
-use environment::{LocalEnv, GraphChain, Fields, LayoutChain};
+use environment::{LocalEnv, GraphChain, Fields, LayoutChain, Scope};
 use io::{Stamp, Io};
-use woot::WString;
+use super::LoomError;
+use encoding::{Encoder, Decoder, DecodeError};
+use json::{JsonWriter, JsonNode};
+use woot::{WString, Op};
+use std::vec;
 use istring::IString;
-pub use source::Placeholder;
+use wheel::Log;
+pub use source::{Placeholder, Span};
 
 /// The Document is a Directed Acyclic Graph.
 ///
@@ -21,13 +26,22 @@ pub use source::Placeholder;
 pub type NodeP = Ptr<Node>;
 pub type NodeListP = Ptr<NodeList<NodeP>>;
 
-pub trait Node {
+/// target of a reference, filled in when resolved or decoded
+pub type Link = Shared<Lock<Option<NodeP>>>;
+
+pub trait Node: MaybeSync {
     /// when building the graph, this method is called
     /// to add child-nodes to the index
     fn childs(&self, &mut Vec<NodeP>) {}
     
+    /// name under which the decoder is registered (see `nodes::decoder`)
+    fn type_name(&self) -> &'static str;
+    
     /// linearize the node
-    //fn encode(&self, e: &mut Encoder);
+    fn encode(&self, e: &mut Encoder);
+    
+    /// JSON representation, see `json`
+    fn to_json(&self, j: &mut JsonWriter) -> JsonNode;
     
     /// one or more child nodes were modified
     fn modified(&self) {}
@@ -35,8 +49,24 @@ pub trait Node {
     /// compute layout graph
     fn layout(&self, env: LayoutChain, w: &mut Writer);
     
-    /// ?
-    fn add_ref(&self, _: &Rc<Node>) {}
+    /// `source` invokes this node
+    fn add_ref(&self, _source: &NodeP) {}
+    
+    /// the node this one invokes, once resolved
+    fn target(&self) -> Option<NodeP> {
+        None
+    }
+    
+    /// Look up the target in `scope`, unless it is known already.
+    /// Returns false if it is still missing.
+    fn resolve(&self, _scope: &Scope) -> bool {
+        true
+    }
+    
+    /// nodes that use this one and have to know when it changes
+    fn references(&self) -> Vec<NodeP> {
+        vec![]
+    }
     
     fn env(&self) -> Option<&LocalEnv> {
         None
@@ -44,35 +74,237 @@ pub trait Node {
     fn fields(&self) -> Option<&Fields> {
         None
     }
+    
+    /// block or macro name
+    fn name(&self) -> Option<&str> {
+        None
+    }
+    
+    /// text of words, symbols and the like
+    fn text(&self) -> Option<&str> {
+        None
+    }
+    
+    /// where the node was declared in the source
+    fn span(&self) -> Option<Span> {
+        None
+    }
+    
+    /// the list of items, for nodes that have one
+    fn list(&self) -> Option<&NodeList<NodeP>> {
+        None
+    }
+    
+    fn placeholder(&self) -> Option<&Placeholder> {
+        None
+    }
+    
+    /// what a macro expects from the blocks invoking it
+    fn signature(&self) -> Option<Signature> {
+        None
+    }
+}
+
+/// The arguments a macro takes. See `Node::signature`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Signature {
+    /// declared names, like `author` in `/quote author`
+    pub params: Vec<IString>,
+    
+    /// None if any number will do (`$args`)
+    pub arity:  Option<usize>
+}
+impl Signature {
+    /// what is wrong with passing `given` arguments
+    pub fn check(&self, given: usize) -> Option<String> {
+        fn arguments(n: usize) -> String {
+            match n {
+                1 => "1 argument".into(),
+                n => format!("{} arguments", n)
+            }
+        }
+        fn are(n: usize) -> &'static str {
+            if n == 1 { "is" } else { "are" }
+        }
+        
+        match self.arity {
+            Some(n) if given < n => Some(format!("takes {}, but only {} {} given", arguments(n), given, are(given))),
+            Some(n) if given > n => Some(format!("takes {}, but {} {} given", arguments(n), given, are(given))),
+            _ => None
+        }
+    }
+}
+
+/// The nodes invoked by `node` or anything below it, and the nodes they invoke in turn.
+/// Each one is listed once.
+pub fn dependencies(node: &NodeP) -> Vec<NodeP> {
+    let mut seen = HashSet::new();
+    let mut out = vec![];
+    let mut queue = vec![node.clone()];
+    
+    while let Some(n) = queue.pop() {
+        if !seen.insert(n.addr()) {
+            continue;
+        }
+        if let Some(target) = n.target() {
+            if !seen.contains(&target.addr()) {
+                out.push(target.clone());
+                queue.push(target);
+            }
+        }
+        n.childs(&mut queue);
+    }
+    out
+}
+
+/// Chains of macros that end up invoking themselves, like `a → b → a`.
+/// The first macro is repeated at the end.
+pub fn cycles(root: &NodeP) -> Vec<Vec<NodeP>> {
+    // the macros invoked directly by the body of `node`
+    fn invoked(node: &NodeP) -> Vec<NodeP> {
+        let mut seen = HashSet::new();
+        let mut out: Vec<NodeP> = vec![];
+        let mut queue = vec![];
+        node.childs(&mut queue);
+        
+        while let Some(n) = queue.pop() {
+            if !seen.insert(n.addr()) {
+                continue;
+            }
+            if let Some(target) = n.target() {
+                if !out.iter().any(|t| t.addr() == target.addr()) {
+                    out.push(target);
+                }
+            }
+            n.childs(&mut queue);
+        }
+        out
+    }
+    
+    fn visit(node: &NodeP, edges: &HashMap<usize, Vec<NodeP>>, path: &mut Vec<NodeP>,
+        done: &mut HashSet<usize>, out: &mut Vec<Vec<NodeP>>)
+    {
+        if let Some(pos) = path.iter().position(|p| p.addr() == node.addr()) {
+            let mut cycle = path[pos ..].to_vec();
+            cycle.push(node.clone());
+            out.push(cycle);
+            return;
+        }
+        if done.contains(&node.addr()) {
+            return;
+        }
+        
+        path.push(node.clone());
+        if let Some(next) = edges.get(&node.addr()) {
+            for n in next {
+                visit(n, edges, path, done, out);
+            }
+        }
+        path.pop();
+        done.insert(node.addr());
+    }
+    
+    let macros = dependencies(root);
+    let edges: HashMap<usize, Vec<NodeP>> = macros.iter()
+        .map(|m| (m.addr(), invoked(m)))
+        .collect();
+    
+    let mut done = HashSet::new();
+    let mut out = vec![];
+    for m in macros.iter() {
+        visit(m, &edges, &mut vec![], &mut done, &mut out);
+    }
+    out
 }
 
 pub struct Ptr<N: ?Sized + Node> {
-    inner: Rc<N>,
+    inner: Shared<N>,
     //references: LinkedList<Ptr<N>>
 }
 impl<N: ?Sized> Node for Ptr<N> where N: Node {
     fn childs(&self, out: &mut Vec<NodeP>) {
         self.inner.childs(out)
     }
+    fn type_name(&self) -> &'static str {
+        self.inner.type_name()
+    }
+    fn encode(&self, e: &mut Encoder) {
+        self.inner.encode(e)
+    }
+    fn to_json(&self, j: &mut JsonWriter) -> JsonNode {
+        self.inner.to_json(j)
+    }
     fn modified(&self) {
         self.inner.modified()
     }
     fn layout(&self, env: LayoutChain, w: &mut Writer) {
         self.inner.layout(env, w)
     }
+    fn add_ref(&self, source: &NodeP) {
+        self.inner.add_ref(source)
+    }
+    fn target(&self) -> Option<NodeP> {
+        self.inner.target()
+    }
+    fn resolve(&self, scope: &Scope) -> bool {
+        self.inner.resolve(scope)
+    }
+    fn references(&self) -> Vec<NodeP> {
+        self.inner.references()
+    }
+    fn env(&self) -> Option<&LocalEnv> {
+        self.inner.env()
+    }
+    fn fields(&self) -> Option<&Fields> {
+        self.inner.fields()
+    }
+    fn list(&self) -> Option<&NodeList<NodeP>> {
+        self.inner.list()
+    }
+    fn placeholder(&self) -> Option<&Placeholder> {
+        self.inner.placeholder()
+    }
+    fn signature(&self) -> Option<Signature> {
+        self.inner.signature()
+    }
+    fn name(&self) -> Option<&str> {
+        self.inner.name()
+    }
+    fn text(&self) -> Option<&str> {
+        self.inner.text()
+    }
+    fn span(&self) -> Option<Span> {
+        self.inner.span()
+    }
 }
 impl<N> Ptr<N> where N: Node {
     pub fn new(n: N) -> Ptr<N> {
         Ptr {
-            inner: Rc::new(n),
+            inner: Shared::new(n),
             //references: LinkedList::new()
         }
     }
 }
+impl<N: ?Sized> Ptr<N> where N: Node {
+    pub fn from_shared(inner: Shared<N>) -> Ptr<N> {
+        Ptr {
+            inner: inner
+        }
+    }
+    
+    pub fn downgrade(&self) -> Weak<N> {
+        Shared::downgrade(&self.inner)
+    }
+    
+    /// identifies the node as long as it is alive
+    pub fn addr(&self) -> usize {
+        &*self.inner as *const N as *const u8 as usize
+    }
+}
 impl<N> From<Ptr<N>> for Ptr<Node> where N: Node + Sized + 'static {
     fn from(n: Ptr<N>) -> Ptr<Node> {
         Ptr {
-            inner: n.inner as Rc<Node>
+            inner: n.inner as Shared<Node>
         }
     }
 }
@@ -86,7 +318,7 @@ impl<N: ?Sized> Deref for Ptr<N> where N: Node {
 }
 impl<N> Ptr<N> where N: Node {
     pub fn get_mut(&mut self) -> Option<&mut N> {
-        Rc::get_mut(&mut self.inner)
+        Shared::get_mut(&mut self.inner)
     }
 }
 impl<N: ?Sized> Clone for Ptr<N> where N: Node {
@@ -97,23 +329,63 @@ impl<N: ?Sized> Clone for Ptr<N> where N: Node {
     }
 }
 
+impl Placeholder {
+    pub fn decode(d: &mut Decoder) -> Result<NodeP, DecodeError> {
+        let p = match d.uint()? {
+            0 => Placeholder::Body,
+            1 => Placeholder::Argument(d.uint()? as usize),
+            2 => Placeholder::Arguments,
+            3 => Placeholder::Unknown(d.str()?),
+            _ => return Err(DecodeError::Invalid("placeholder"))
+        };
+        Ok(Ptr::new(p).into())
+    }
+}
 impl Node for Placeholder {
-    fn layout(&self, env: LayoutChain, w: &mut Writer) {
-        let n = {
-            let fields = env.fields()
-            .expect("Placeholder::layout: no fields found!");
-            let n: Option<NodeP> = match self {
-                &Placeholder::Body => fields.body.clone().map(|n| n.into()),
-                &Placeholder::Argument(i) => fields.args.clone()
-                    .and_then(|n| n.iter().nth(i).cloned()),
-                &Placeholder::Arguments => fields.args.clone().map(|n| n.into()),
-                _ => None
-            };
-            n
+    fn type_name(&self) -> &'static str {
+        "placeholder"
+    }
+    fn encode(&self, e: &mut Encoder) {
+        match *self {
+            Placeholder::Body => e.uint(0),
+            Placeholder::Argument(n) => {
+                e.uint(1);
+                e.uint(n as u64);
+            },
+            Placeholder::Arguments => e.uint(2),
+            Placeholder::Unknown(ref s) => {
+                e.uint(3);
+                e.str(s);
+            }
+        }
+    }
+    fn to_json(&self, _j: &mut JsonWriter) -> JsonNode {
+        let name = match *self {
+            Placeholder::Body => "body".into(),
+            Placeholder::Argument(n) => n.to_string(),
+            Placeholder::Arguments => "args".into(),
+            Placeholder::Unknown(ref s) => s.to_string()
         };
-        n.map(|n| n.layout(env, w))
+        JsonNode::Placeholder { name: name }
+    }
+    fn placeholder(&self) -> Option<&Placeholder> {
+        Some(self)
+    }
+    fn layout(&self, env: LayoutChain, w: &mut Writer) {
+        let n: Option<NodeP> = env.fields().and_then(|fields| match self {
+            &Placeholder::Body => fields.body.clone().map(|n| n.into()),
+            &Placeholder::Argument(i) => fields.args.as_ref()
+                .and_then(|n| n.get(i)),
+            &Placeholder::Arguments => fields.args.clone().map(|n| n.into()),
+            _ => None
+        });
+        // what was passed in belongs to the invocation, not to the macro
+        n.map(|n| n.layout(env.outside(), w))
         .unwrap_or_else(|| {
-            println!("no macro set");
+            match self {
+                &Placeholder::Unknown(ref s) => warn!(Log::root(), "unknown parameter: ${}", s),
+                _ => warn!(Log::root(), "missing argument: {:?}", self)
+            }
             match self {
                 &Placeholder::Body => w.word(Atom::normal("$body")),
                 &Placeholder::Argument(n) => w.word(Atom::normal(&format!("${}", n))),
@@ -126,19 +398,35 @@ impl Node for Placeholder {
 
 pub struct Ref {
     name: String,
-    target: RefCell<Option<NodeP>>
+    target: Link
 }
 impl Ref {
     pub fn new(name: String) -> Ref {
         Ref {
             name:   name,
-            target: RefCell::new(None)
+            target: Shared::new(Lock::new(None))
         }
     }
+    pub fn encode(&self, e: &mut Encoder) {
+        e.str(&self.name);
+        e.link(self.get());
+    }
+    pub fn decode(d: &mut Decoder) -> Result<Ref, DecodeError> {
+        let r = Ref::new(d.str()?.to_string());
+        d.link(&r.target)?;
+        Ok(r)
+    }
     pub fn resolve(self, env: &GraphChain) -> Ref {
         *self.target.borrow_mut() = env.get_target(&self.name).cloned();
         self
     }
+    /// see `Node::resolve`
+    pub fn resolve_in(&self, scope: &Scope) -> bool {
+        if self.target.borrow().is_none() {
+            *self.target.borrow_mut() = scope.get_target(&self.name).cloned();
+        }
+        self.target.borrow().is_some()
+    }
     pub fn get(&self) -> Option<NodeP> {
         match *self.target.borrow() {
             Some(ref n) => Some(n.clone()),
@@ -152,20 +440,38 @@ impl Ref {
 
 pub struct GroupRef {
     key: (IString, IString),
-    target: RefCell<Option<NodeP>>
+    target: Link
 }
 impl GroupRef {
     pub fn new(opening: IString, closing: IString) -> GroupRef {
         GroupRef {
             key:    (opening, closing),
-            target: RefCell::new(None)
+            target: Shared::new(Lock::new(None))
         }
     }
+    pub fn encode(&self, e: &mut Encoder) {
+        e.str(&self.key.0);
+        e.str(&self.key.1);
+        e.link(self.get());
+    }
+    pub fn decode(d: &mut Decoder) -> Result<GroupRef, DecodeError> {
+        let opening = d.str()?;
+        let closing = d.str()?;
+        let g = GroupRef::new(opening, closing);
+        d.link(&g.target)?;
+        Ok(g)
+    }
     pub fn resolve(&mut self, env: &GraphChain) {
         if let Some(target) = env.get_group(&self.key) {
             *self.target.borrow_mut() = Some(target.clone());
         }
     }
+    /// Groups without a target are fine. They show their delimiters.
+    pub fn resolve_in(&self, scope: &Scope) {
+        if self.target.borrow().is_none() {
+            *self.target.borrow_mut() = scope.get_group(&self.key).cloned();
+        }
+    }
     pub fn get(&self) -> Option<NodeP> {
         match *self.target.borrow() {
             Some(ref n) => Some(n.clone()),
@@ -177,41 +483,184 @@ impl GroupRef {
     }
 }
 
+/// An operation on a `NodeList`
+pub type ListOp = Op<NodeP, Stamp>;
+
 pub struct NodeList<T: Sized + Node + Clone> {
-    ws: WString<T, Stamp>
+    // identifies the list in the journal
+    stamp:  Stamp,
+    ws:     Lock<WString<T, Stamp>>
 }
-impl<T> NodeList<T> where T: Node + Clone {
-    pub fn iter<'a>(&'a self) -> impl Iterator<Item=&'a T> {
+/// The items of a `NodeList`, which is locked meanwhile.
+pub struct Items<'a, T: 'a> {
+    ws: ReadGuard<'a, WString<T, Stamp>>
+}
+impl<'a, T: 'a> Items<'a, T> {
+    pub fn iter<'b>(&'b self) -> impl Iterator<Item=&'b T> + 'b {
         self.ws.iter()
     }
+}
+
+impl<T> NodeList<T> where T: Node + Clone {
+    /// a copy of the items at the time of the call
+    pub fn iter(&self) -> vec::IntoIter<T> {
+        self.ws.borrow().iter().cloned().collect::<Vec<T>>().into_iter()
+    }
+    
+    /// The items without copying them.
+    /// The list can not be modified while they are held.
+    pub fn items(&self) -> Items<T> {
+        Items { ws: self.ws.borrow() }
+    }
     pub fn from<I>(io: &Io, iter: I) -> NodeList<T>
     where I: Iterator<Item=T> {
         let mut ws = WString::new();
         
         for (n, item) in iter.enumerate() {
             let job = io.create();
-            let _op = ws.ins(n, item, job.stamp());
-            //job.submit(op);
-            
-            //emit();
+            ws.ins(n, item, job.stamp());
         }
         
         NodeList {
-            ws: ws
+            stamp:  io.create().stamp(),
+            ws:     Lock::new(ws)
         }
     }
+    pub fn stamp(&self) -> Stamp {
+        self.stamp
+    }
     pub fn size(&self) -> usize {
-        self.ws.len()
+        self.ws.borrow().len()
+    }
+    pub fn get(&self, n: usize) -> Option<T> {
+        self.ws.borrow().iter().nth(n).cloned()
+    }
+    
+    /// Apply an operation from the journal or another replica.
+    /// It is returned if it depends on an item that is not known yet.
+    pub fn apply(&self, op: Op<T, Stamp>) -> Result<(), Op<T, Stamp>> {
+        self.ws.borrow_mut().apply(op)
     }
 }
-impl<T> Node for NodeList<T> where T: Node + Sized + Clone + Into<NodeP> {
-    fn childs(&self, out: &mut Vec<NodeP>) {
-        for n in self.ws.iter() {
-            out.push(n.clone().into());
+impl NodeList<NodeP> {
+    /// Insert `item` at `pos` and record the operation.
+    pub fn insert(&self, io: &Io, pos: usize, item: NodeP) -> Result<(), LoomError> {
+        if pos > self.size() {
+            return Err(LoomError::InvalidArg("position"));
         }
+        let id = io.create().stamp();
+        let op = self.ws.borrow_mut().ins(pos, item, id);
+        io.submit(self.stamp, &op);
+        Ok(())
+    }
+    
+    /// Remove the item at `pos` and record the operation.
+    pub fn remove(&self, io: &Io, pos: usize) -> Result<NodeP, LoomError> {
+        let item = match self.get(pos) {
+            Some(item) => item,
+            None => return Err(LoomError::InvalidArg("position"))
+        };
+        let op = self.ws.borrow_mut().del(pos);
+        io.submit(self.stamp, &op);
+        Ok(item)
+    }
+    
+    /// Replace the item at `pos`. The old one is returned.
+    pub fn replace(&self, io: &Io, pos: usize, item: NodeP) -> Result<NodeP, LoomError> {
+        let old = match self.get(pos) {
+            Some(old) => old,
+            None => return Err(LoomError::InvalidArg("position"))
+        };
+        let id = io.create().stamp();
+        let ops = {
+            let mut ws = self.ws.borrow_mut();
+            let del = ws.del(pos);
+            let ins = ws.ins(pos, item, id);
+            [del, ins]
+        };
+        io.submit_all(self.stamp, &ops);
+        Ok(old)
+    }
+    
+    /// The items are owned by the list.
+    /// Their ids are kept, so later operations still apply.
+    pub fn encode_items(&self, e: &mut Encoder) {
+        e.stamp(self.stamp);
+        let ws = self.ws.borrow();
+        e.array(ws.len());
+        for (&id, n) in ws.ids().zip(ws.iter()) {
+            e.stamp(id);
+            e.node(n);
+        }
+    }
+    pub fn decode_items(d: &mut Decoder) -> Result<NodeList<NodeP>, DecodeError> {
+        let stamp = d.stamp()?;
+        let mut ws = WString::new();
+        for n in 0 .. d.array()? {
+            let id = d.stamp()?;
+            ws.ins(n, d.node()?, id);
+        }
+        Ok(NodeList {
+            stamp:  stamp,
+            ws:     Lock::new(ws)
+        })
+    }
+    pub fn decode(d: &mut Decoder) -> Result<NodeP, DecodeError> {
+        let list = Ptr::new(NodeList::decode_items(d)?);
+        d.add_list(list.clone());
+        Ok(list.into())
+    }
+    
+    pub fn encode_op(op: &ListOp, e: &mut Encoder) {
+        match *op {
+            Op::Ins { id, prev, next, ref item } => {
+                e.uint(0);
+                e.stamp(id);
+                for &s in [prev, next].iter() {
+                    match s {
+                        Some(s) => e.stamp(s),
+                        None => e.nil()
+                    }
+                }
+                e.node(item);
+            },
+            Op::Del { id } => {
+                e.uint(1);
+                e.stamp(id);
+            }
+        }
+    }
+    pub fn decode_op(d: &mut Decoder) -> Result<ListOp, DecodeError> {
+        match d.uint()? {
+            0 => {
+                let id = d.stamp()?;
+                let prev = if d.nil()? { None } else { Some(d.stamp()?) };
+                let next = if d.nil()? { None } else { Some(d.stamp()?) };
+                Ok(Op::Ins { id: id, prev: prev, next: next, item: d.node()? })
+            },
+            1 => Ok(Op::Del { id: d.stamp()? }),
+            _ => Err(DecodeError::Invalid("list op"))
+        }
+    }
+}
+impl Node for NodeList<NodeP> {
+    fn type_name(&self) -> &'static str {
+        "nodelist"
+    }
+    fn encode(&self, e: &mut Encoder) {
+        self.encode_items(e)
+    }
+    fn to_json(&self, j: &mut JsonWriter) -> JsonNode {
+        JsonNode::NodeList { items: j.list(self) }
+    }
+    fn childs(&self, out: &mut Vec<NodeP>) {
+        out.extend(self.items().iter().cloned());
+    }
+    fn list(&self) -> Option<&NodeList<NodeP>> {
+        Some(self)
     }
     fn layout(&self, env: LayoutChain, w: &mut Writer) {
-        for n in self.iter() {
+        for n in self.items().iter() {
             n.layout(env.clone(), w);
         }
     }
@@ -0,0 +1,716 @@
+//! MessagePack representation of the document graph.
+//!
+//! A stream starts with a header `["loom", VERSION]`, followed by `StreamItem`s.
+//! Every node is written after the nodes it owns, so a node can be built
+//! as soon as its record is read.
+//! Links to macros (`Ref`, `GroupRef`) may point forward; they are filled in
+//! once the whole stream is read.
+//!
+//! The same format is used for saved documents and for the journal.
+
+use std::collections::{HashMap, HashSet};
+use shared::Shared;
+use std::mem;
+use std::str;
+use rmp::encode as enc;
+use rmp::decode as dec;
+use rmp::decode::{ValueReadError, NumValueReadError};
+use woot::IncrementalStamper;
+use document::{Node, NodeP, NodeList, NodeListP, ListOp, Ptr, Link};
+use hyphenation::{Hyphenator, HyphenatorFiles};
+use source::Span;
+use layout::Glue;
+use io::{Stamp, TypeId, DataSize};
+use istring::IString;
+
+pub const MAGIC: &'static str = "loom";
+pub const VERSION: u32 = 1;
+
+/// Builds a node from its data, in the order `Node::encode` wrote it.
+pub type DecodeFn = fn(&mut Decoder) -> Result<NodeP, DecodeError>;
+
+// record tags
+const NODE_DECL:    u8 = 0;
+const CREATE_NODE:  u8 = 1;
+const DELETE_NODE:  u8 = 2;
+const OP:           u8 = 3;
+const ROOT:         u8 = 4;
+const HYPHENATOR:   u8 = 5;
+
+/// One record of the stream.
+#[derive(Debug, PartialEq)]
+pub enum StreamItem {
+    /// add a new node type to the known types.
+    /// The type ids are given in the order of declaration.
+    NodeDecl(IString),
+
+    /// followed by `DataSize` bytes of node data
+    CreateNode(TypeId, DataSize, Stamp),
+    DeleteNode(Stamp),
+
+    /// an operation on the list with the given stamp,
+    /// followed by `DataSize` bytes
+    Op(Stamp, DataSize),
+
+    /// the root node of the document
+    Root(Stamp),
+
+    /// the following nodes use this hyphenator.
+    /// It has to be loaded before decoding.
+    Hyphenator(HyphenatorFiles)
+}
+impl StreamItem {
+    pub fn write(&self, out: &mut Vec<u8>) {
+        match *self {
+            StreamItem::NodeDecl(ref name) => {
+                enc::write_array_len(out, 2).unwrap();
+                enc::write_uint(out, NODE_DECL as u64).unwrap();
+                enc::write_str(out, name).unwrap();
+            },
+            StreamItem::CreateNode(type_id, size, stamp) => {
+                enc::write_array_len(out, 5).unwrap();
+                enc::write_uint(out, CREATE_NODE as u64).unwrap();
+                enc::write_uint(out, type_id as u64).unwrap();
+                enc::write_uint(out, size as u64).unwrap();
+                write_stamp(out, stamp);
+            },
+            StreamItem::DeleteNode(stamp) => {
+                enc::write_array_len(out, 3).unwrap();
+                enc::write_uint(out, DELETE_NODE as u64).unwrap();
+                write_stamp(out, stamp);
+            },
+            StreamItem::Op(stamp, size) => {
+                enc::write_array_len(out, 4).unwrap();
+                enc::write_uint(out, OP as u64).unwrap();
+                write_stamp(out, stamp);
+                enc::write_uint(out, size as u64).unwrap();
+            },
+            StreamItem::Root(stamp) => {
+                enc::write_array_len(out, 3).unwrap();
+                enc::write_uint(out, ROOT as u64).unwrap();
+                write_stamp(out, stamp);
+            },
+            StreamItem::Hyphenator(ref files) => {
+                enc::write_array_len(out, 3).unwrap();
+                enc::write_uint(out, HYPHENATOR as u64).unwrap();
+                write_files(out, files);
+            }
+        }
+    }
+
+    pub fn read(data: &mut &[u8]) -> Result<StreamItem, DecodeError> {
+        let len = dec::read_array_len(data)?;
+        let tag: u8 = dec::read_int(data)?;
+        let item = match (tag, len) {
+            (NODE_DECL, 2) => StreamItem::NodeDecl(read_str(data)?),
+            (CREATE_NODE, 5) => {
+                let type_id = dec::read_int(data)?;
+                let size = dec::read_int(data)?;
+                StreamItem::CreateNode(type_id, size, read_stamp(data)?)
+            },
+            (DELETE_NODE, 3) => StreamItem::DeleteNode(read_stamp(data)?),
+            (OP, 4) => {
+                let stamp = read_stamp(data)?;
+                StreamItem::Op(stamp, dec::read_int(data)?)
+            },
+            (ROOT, 3) => StreamItem::Root(read_stamp(data)?),
+            (HYPHENATOR, 3) => StreamItem::Hyphenator(read_files(data)?),
+            _ => return Err(DecodeError::Invalid("stream item"))
+        };
+        Ok(item)
+    }
+
+    /// number of data bytes following the record
+    pub fn size(&self) -> usize {
+        match *self {
+            StreamItem::CreateNode(_, size, _) | StreamItem::Op(_, size) => size as usize,
+            _ => 0
+        }
+    }
+}
+
+pub fn header() -> Vec<u8> {
+    let mut out = Vec::new();
+    enc::write_array_len(&mut out, 2).unwrap();
+    enc::write_str(&mut out, MAGIC).unwrap();
+    enc::write_uint(&mut out, VERSION as u64).unwrap();
+    out
+}
+
+pub fn read_header(data: &mut &[u8]) -> Result<(), DecodeError> {
+    if dec::read_array_len(data)? != 2 {
+        return Err(DecodeError::Header);
+    }
+    if &*read_str(data)? != MAGIC {
+        return Err(DecodeError::Header);
+    }
+    let version: u32 = dec::read_int(data)?;
+    if version != VERSION {
+        return Err(DecodeError::Header);
+    }
+    Ok(())
+}
+
+/// The hyphenators a stream (without header) asks for
+pub fn hyphenators(mut data: &[u8]) -> Result<Vec<HyphenatorFiles>, DecodeError> {
+    let mut names = vec![];
+    while data.len() > 0 {
+        let item = StreamItem::read(&mut data)?;
+        if data.len() < item.size() {
+            return Err(DecodeError::Eof);
+        }
+        data = &data[item.size() ..];
+
+        if let StreamItem::Hyphenator(files) = item {
+            names.push(files);
+        }
+    }
+    Ok(names)
+}
+
+fn write_stamp(out: &mut Vec<u8>, stamp: Stamp) {
+    enc::write_uint(out, stamp.0 as u64).unwrap();
+    enc::write_uint(out, stamp.1 as u64).unwrap();
+}
+fn read_stamp(data: &mut &[u8]) -> Result<Stamp, DecodeError> {
+    let a = dec::read_int(data)?;
+    let b = dec::read_int(data)?;
+    Ok((a, b))
+}
+// the dictionary and the pattern file, each a string or nil
+fn write_files(out: &mut Vec<u8>, files: &HyphenatorFiles) {
+    for file in [&files.dictionary, &files.patterns].iter() {
+        match **file {
+            Some(ref name) => enc::write_str(out, name).unwrap(),
+            None => enc::write_nil(out).unwrap()
+        }
+    }
+}
+fn read_files(data: &mut &[u8]) -> Result<HyphenatorFiles, DecodeError> {
+    let mut file = || -> Result<Option<IString>, DecodeError> {
+        match data.first() {
+            Some(&0xc0) => {
+                dec::read_nil(&mut *data)?;
+                Ok(None)
+            },
+            Some(_) => Ok(Some(read_str(&mut *data)?)),
+            None => Err(DecodeError::Eof)
+        }
+    };
+    let dictionary = file()?;
+    let patterns = file()?;
+    Ok(HyphenatorFiles {
+        dictionary: dictionary,
+        patterns:   patterns
+    })
+}
+fn read_str(data: &mut &[u8]) -> Result<IString, DecodeError> {
+    let len = dec::read_str_len(data)? as usize;
+    if data.len() < len {
+        return Err(DecodeError::Eof);
+    }
+    let (s, rest) = data.split_at(len);
+    *data = rest;
+    match str::from_utf8(s) {
+        Ok(s) => Ok(s.into()),
+        Err(_) => Err(DecodeError::Utf8)
+    }
+}
+
+#[derive(Debug)]
+pub enum DecodeError {
+    Read(ValueReadError),
+    Number(NumValueReadError),
+    Eof,
+    Utf8,
+
+    /// not a loom stream, or an unsupported version
+    Header,
+    UnknownType(IString),
+    UnknownTypeId(TypeId),
+    UnknownNode(Stamp),
+    MissingHyphenator(HyphenatorFiles),
+    Invalid(&'static str)
+}
+impl From<ValueReadError> for DecodeError {
+    fn from(e: ValueReadError) -> DecodeError {
+        DecodeError::Read(e)
+    }
+}
+impl From<NumValueReadError> for DecodeError {
+    fn from(e: NumValueReadError) -> DecodeError {
+        DecodeError::Number(e)
+    }
+}
+
+/// What the reader of a stream knows already.
+/// The journal keeps it between records.
+#[derive(Default)]
+pub struct StreamState {
+    types:          HashMap<&'static str, TypeId>,
+    hyphenators:    HashSet<HyphenatorFiles>,
+    written:        HashSet<Stamp>
+}
+impl StreamState {
+    pub fn new() -> StreamState {
+        StreamState::default()
+    }
+    
+    /// the reader has the node already
+    pub fn known(&mut self, stamp: Stamp) {
+        self.written.insert(stamp);
+    }
+    
+    /// Start a new stream to the same reader.
+    /// Types and hyphenators are declared again, nodes are not written again.
+    pub fn restart(&mut self) {
+        self.types.clear();
+        self.hyphenators.clear();
+    }
+}
+
+pub struct Encoder<'a> {
+    // the finished records
+    out:            Vec<u8>,
+
+    // data of the node currently being encoded
+    data:           Vec<u8>,
+
+    // linked nodes that still have to be written
+    pending:        Vec<NodeP>,
+
+    state:          &'a mut StreamState,
+
+    // node address -> stamp, of the registered nodes
+    ids:            &'a HashMap<usize, Stamp>,
+    
+    // the others, only while they are kept alive by the graph being written
+    fresh:          HashMap<usize, Stamp>,
+    stamper:        &'a mut IncrementalStamper<u32, u32>
+}
+impl<'a> Encoder<'a> {
+    /// Nodes that have an entry in `ids` keep their stamp,
+    /// the others get a new one from the stamper for as long as the encoder lives.
+    pub fn new(state: &'a mut StreamState, ids: &'a HashMap<usize, Stamp>,
+        stamper: &'a mut IncrementalStamper<u32, u32>) -> Encoder<'a>
+    {
+        Encoder {
+            out:            Vec::new(),
+            data:           Vec::new(),
+            pending:        Vec::new(),
+            state:          state,
+            ids:            ids,
+            fresh:          HashMap::new(),
+            stamper:        stamper
+        }
+    }
+
+    /// write the node and everything it needs
+    pub fn write<N: Node + ?Sized>(&mut self, node: &Ptr<N>) -> Stamp {
+        self.write_node(node)
+    }
+
+    /// write an operation on the list with the given stamp
+    pub fn write_op(&mut self, list: Stamp, op: &ListOp) {
+        let outer = mem::replace(&mut self.data, Vec::new());
+        NodeList::encode_op(op, self);
+        let data = mem::replace(&mut self.data, outer);
+
+        StreamItem::Op(list, data.len() as DataSize).write(&mut self.out);
+        self.out.extend_from_slice(&data);
+    }
+
+    pub fn write_item(&mut self, item: StreamItem) {
+        item.write(&mut self.out);
+    }
+
+    /// the records written so far, including the linked nodes
+    pub fn finish(mut self) -> Vec<u8> {
+        while let Some(node) = self.pending.pop() {
+            self.write_node(&node);
+        }
+        self.out
+    }
+
+    // An address can be reused once the node is gone,
+    // so it is only remembered while the node can not be dropped.
+    fn stamp_of<N: Node + ?Sized>(&mut self, node: &Ptr<N>) -> Stamp {
+        if let Some(&stamp) = self.ids.get(&node.addr()) {
+            return stamp;
+        }
+        let stamper = &mut self.stamper;
+        *self.fresh.entry(node.addr())
+        .or_insert_with(|| stamper.stamp())
+    }
+
+    // writes the node (after everything it owns) unless it is known already
+    fn write_node<N: Node + ?Sized>(&mut self, node: &Ptr<N>) -> Stamp {
+        let stamp = self.stamp_of(node);
+        if self.state.written.contains(&stamp) {
+            return stamp;
+        }
+
+        let outer = mem::replace(&mut self.data, Vec::new());
+        node.encode(self);
+        let data = mem::replace(&mut self.data, outer);
+
+        let type_name = node.type_name();
+        let type_id = match self.state.types.get(type_name) {
+            Some(&id) => id,
+            None => {
+                let id = self.state.types.len() as TypeId;
+                self.state.types.insert(type_name, id);
+                StreamItem::NodeDecl(type_name.into()).write(&mut self.out);
+                id
+            }
+        };
+
+        StreamItem::CreateNode(type_id, data.len() as DataSize, stamp).write(&mut self.out);
+        self.out.extend_from_slice(&data);
+        self.state.written.insert(stamp);
+
+        stamp
+    }
+
+    /// a node owned by the current one
+    pub fn node<N: Node + ?Sized>(&mut self, node: &Ptr<N>) {
+        let stamp = self.write_node(node);
+        write_stamp(&mut self.data, stamp);
+    }
+
+    /// a node that is only referred to. It may be written later on.
+    pub fn link(&mut self, node: Option<NodeP>) {
+        match node {
+            Some(node) => {
+                let stamp = self.stamp_of(&node);
+                if !self.state.written.contains(&stamp) {
+                    self.pending.push(node);
+                }
+                write_stamp(&mut self.data, stamp);
+            },
+            None => self.nil()
+        }
+    }
+
+    pub fn stamp(&mut self, stamp: Stamp) {
+        write_stamp(&mut self.data, stamp);
+    }
+    pub fn str(&mut self, s: &str) {
+        enc::write_str(&mut self.data, s).unwrap();
+    }
+    pub fn uint(&mut self, n: u64) {
+        enc::write_uint(&mut self.data, n).unwrap();
+    }
+    pub fn bool(&mut self, b: bool) {
+        enc::write_bool(&mut self.data, b).unwrap();
+    }
+    pub fn nil(&mut self) {
+        enc::write_nil(&mut self.data).unwrap();
+    }
+    pub fn array(&mut self, len: usize) {
+        enc::write_array_len(&mut self.data, len as u32).unwrap();
+    }
+    pub fn glue(&mut self, glue: Glue) {
+        match glue {
+            Glue::None => {
+                self.array(1);
+                self.uint(0);
+            },
+            Glue::Space { breaking, scale } => {
+                self.array(3);
+                self.uint(1);
+                self.bool(breaking);
+                enc::write_f32(&mut self.data, scale).unwrap();
+            },
+            Glue::Newline { fill } => {
+                self.array(2);
+                self.uint(2);
+                self.bool(fill);
+            }
+        }
+    }
+
+    pub fn span(&mut self, span: &Span) {
+        match span.file {
+            Some(ref file) => {
+                self.array(3);
+                self.uint(span.offset as u64);
+                self.uint(span.line as u64);
+                self.str(file);
+            },
+            None => {
+                self.array(2);
+                self.uint(span.offset as u64);
+                self.uint(span.line as u64);
+            }
+        }
+    }
+
+    /// the hyphenator has to be loaded before the document can be decoded
+    pub fn hyphenator(&mut self, files: &HyphenatorFiles) {
+        if !self.state.hyphenators.contains(files) {
+            self.state.hyphenators.insert(files.clone());
+            StreamItem::Hyphenator(files.clone()).write(&mut self.out);
+        }
+        write_files(&mut self.data, files);
+    }
+}
+
+/// The result of decoding a stream
+pub struct Decoded {
+    /// the last root record, if any
+    pub root:       Option<NodeP>,
+
+    /// the nodes that were created
+    pub nodes:      HashMap<Stamp, NodeP>,
+
+    pub deleted:    Vec<Stamp>,
+
+    /// operations that were applied, by list
+    pub applied:    Vec<(Stamp, ListOp)>,
+
+    /// operations that depend on something that is not known yet
+    pub pending:    Vec<(Stamp, ListOp)>
+}
+
+pub struct Decoder<'a> {
+    data:           &'a [u8],
+    types:          Vec<DecodeFn>,
+
+    // known nodes (including those of the receiving machine)
+    nodes:          HashMap<Stamp, NodeP>,
+
+    // created by this stream
+    created:        HashMap<Stamp, NodeP>,
+
+    // lists are needed with their type
+    lists:          HashMap<Stamp, NodeListP>,
+
+    // list stamp -> node containing the list
+    owners:         HashMap<Stamp, NodeP>,
+
+    // stamp of the node being decoded
+    current:        Stamp,
+
+    // links to fill in at the end
+    links:          Vec<(Stamp, Link)>,
+
+    hyphenators:    HashMap<HyphenatorFiles, Shared<Hyphenator>>
+}
+impl<'a> Decoder<'a> {
+    /// `data` starts after the header
+    pub fn new(data: &'a [u8], hyphenators: HashMap<HyphenatorFiles, Shared<Hyphenator>>) -> Decoder<'a> {
+        Decoder {
+            data:           data,
+            types:          Vec::new(),
+            nodes:          HashMap::new(),
+            created:        HashMap::new(),
+            lists:          HashMap::new(),
+            owners:         HashMap::new(),
+            current:        (0, 0),
+            links:          Vec::new(),
+            hyphenators:    hyphenators
+        }
+    }
+
+    /// The stream may refer to these nodes
+    pub fn known<'b, I>(&mut self, nodes: I) where I: Iterator<Item=(&'b Stamp, &'b NodeP)> {
+        for (&stamp, node) in nodes {
+            self.add(stamp, node.clone());
+        }
+    }
+
+    fn add(&mut self, stamp: Stamp, node: NodeP) {
+        if let Some(list) = node.list() {
+            self.owners.insert(list.stamp(), node.clone());
+        }
+        self.nodes.insert(stamp, node);
+    }
+
+    /// Decode all records.
+    pub fn run(mut self) -> Result<Decoded, DecodeError> {
+        use nodes;
+
+        let mut root = None;
+        let mut deleted = vec![];
+        let mut applied = vec![];
+        let mut pending = vec![];
+
+        while self.data.len() > 0 {
+            let item = StreamItem::read(&mut self.data)?;
+            let remaining = self.data.len();
+            if remaining < item.size() {
+                return Err(DecodeError::Eof);
+            }
+
+            match item {
+                StreamItem::NodeDecl(ref name) => {
+                    match nodes::decoder(name) {
+                        Some(f) => self.types.push(f),
+                        None => return Err(DecodeError::UnknownType(name.clone()))
+                    }
+                },
+                StreamItem::CreateNode(_, size, stamp) if self.nodes.contains_key(&stamp) => {
+                    // the receiver has it already
+                    self.data = &self.data[size as usize ..];
+                },
+                StreamItem::CreateNode(type_id, _, stamp) => {
+                    let f = match self.types.get(type_id as usize) {
+                        Some(&f) => f,
+                        None => return Err(DecodeError::UnknownTypeId(type_id))
+                    };
+                    self.current = stamp;
+                    let node = f(&mut self)?;
+                    self.created.insert(stamp, node.clone());
+                    self.add(stamp, node);
+                },
+                StreamItem::DeleteNode(stamp) => {
+                    self.nodes.remove(&stamp);
+                    self.created.remove(&stamp);
+                    deleted.push(stamp);
+                },
+                StreamItem::Op(list, _) => {
+                    let op = NodeList::decode_op(&mut self)?;
+                    let unapplied = match self.owners.get(&list) {
+                        Some(owner) => owner.list().unwrap().apply(op.clone()).err(),
+                        None => Some(op.clone())
+                    };
+                    match unapplied {
+                        Some(op) => pending.push((list, op)),
+                        None => applied.push((list, op))
+                    }
+                },
+                StreamItem::Root(stamp) => match self.nodes.get(&stamp) {
+                    Some(node) => root = Some(node.clone()),
+                    None => return Err(DecodeError::UnknownNode(stamp))
+                },
+                StreamItem::Hyphenator(_) => {}
+            }
+
+            if remaining - self.data.len() != item.size() {
+                return Err(DecodeError::Invalid("record size"));
+            }
+        }
+
+        for (stamp, link) in self.links.drain(..) {
+            match self.nodes.get(&stamp) {
+                Some(node) => *link.borrow_mut() = Some(node.clone()),
+                None => return Err(DecodeError::UnknownNode(stamp))
+            }
+        }
+
+        // back-references are not encoded
+        for node in self.created.values() {
+            if let Some(target) = node.target() {
+                target.add_ref(node);
+            }
+        }
+
+        Ok(Decoded {
+            root:       root,
+            nodes:      self.created,
+            deleted:    deleted,
+            applied:    applied,
+            pending:    pending
+        })
+    }
+
+    /// a node that was written before the current one
+    pub fn node(&mut self) -> Result<NodeP, DecodeError> {
+        let stamp = read_stamp(&mut self.data)?;
+        match self.nodes.get(&stamp) {
+            Some(node) => Ok(node.clone()),
+            None => Err(DecodeError::UnknownNode(stamp))
+        }
+    }
+
+    /// a list that was written before the current node
+    pub fn list(&mut self) -> Result<NodeListP, DecodeError> {
+        let stamp = read_stamp(&mut self.data)?;
+        match self.lists.get(&stamp) {
+            Some(list) => Ok(list.clone()),
+            None => Err(DecodeError::UnknownNode(stamp))
+        }
+    }
+
+    /// called by `NodeList::decode`
+    pub fn add_list(&mut self, list: NodeListP) {
+        self.lists.insert(self.current, list);
+    }
+
+    /// fill in the link once all nodes are known
+    pub fn link(&mut self, link: &Link) -> Result<(), DecodeError> {
+        if self.nil()? {
+            return Ok(());
+        }
+        let stamp = read_stamp(&mut self.data)?;
+        self.links.push((stamp, link.clone()));
+        Ok(())
+    }
+
+    pub fn stamp(&mut self) -> Result<Stamp, DecodeError> {
+        read_stamp(&mut self.data)
+    }
+    pub fn str(&mut self) -> Result<IString, DecodeError> {
+        read_str(&mut self.data)
+    }
+    pub fn uint(&mut self) -> Result<u64, DecodeError> {
+        Ok(dec::read_int(&mut self.data)?)
+    }
+    pub fn bool(&mut self) -> Result<bool, DecodeError> {
+        Ok(dec::read_bool(&mut self.data)?)
+    }
+
+    /// consumes a nil, if there is one
+    pub fn nil(&mut self) -> Result<bool, DecodeError> {
+        match self.data.first() {
+            Some(&0xc0) => {
+                dec::read_nil(&mut self.data)?;
+                Ok(true)
+            },
+            Some(_) => Ok(false),
+            None => Err(DecodeError::Eof)
+        }
+    }
+    pub fn array(&mut self) -> Result<usize, DecodeError> {
+        Ok(dec::read_array_len(&mut self.data)? as usize)
+    }
+    pub fn glue(&mut self) -> Result<Glue, DecodeError> {
+        let len = self.array()?;
+        let glue = match (self.uint()?, len) {
+            (0, 1) => Glue::None,
+            (1, 3) => {
+                let breaking = self.bool()?;
+                let scale = dec::read_f32(&mut self.data)?;
+                Glue::Space { breaking: breaking, scale: scale }
+            },
+            (2, 2) => Glue::Newline { fill: self.bool()? },
+            _ => return Err(DecodeError::Invalid("glue"))
+        };
+        Ok(glue)
+    }
+    pub fn span(&mut self) -> Result<Span, DecodeError> {
+        let len = self.array()?;
+        if len != 2 && len != 3 {
+            return Err(DecodeError::Invalid("span"));
+        }
+        let offset = self.uint()? as usize;
+        let line = self.uint()? as usize;
+        let file = match len {
+            3 => Some(self.str()?),
+            _ => None
+        };
+        Ok(Span {
+            offset: offset,
+            line:   line,
+            file:   file
+        })
+    }
+    pub fn hyphenator(&mut self) -> Result<(HyphenatorFiles, Shared<Hyphenator>), DecodeError> {
+        let files = read_files(&mut self.data)?;
+        match self.hyphenators.get(&files) {
+            Some(h) => Ok((files.clone(), h.clone())),
+            None => Err(DecodeError::MissingHyphenator(files))
+        }
+    }
+}
@@ -1,14 +1,19 @@
-use std::collections::HashMap;
-use std::rc::Rc;
+use std::collections::{HashMap, HashSet};
+use shared::Shared;
 use std::ops::Deref;
 use document::{Node, NodeP, NodeListP};
 use io::{Io};
-use hyphenation::Hyphenator;
+use hyphenation::{Hyphenator, HyphenatorFiles, Hyphen};
+use parser::SOFT_HYPHEN;
+use language::Language;
 use commands::Command;
 use indexmap::IndexMap;
-use layout::{Atom, Glue, Writer};
+use layout::{Atom, Glue, Writer, Marks};
 use wheel::{Directory};
 use istring::IString;
+use encoding::{Encoder, Decoder, DecodeError};
+use json::{JsonWriter, JsonReader, JsonEnv, JsonTarget, JsonGroup};
+use LoomError;
 
 /// The Environment can only be changed within the Block::parse call
 /// Is is therefore allowed to cache results whithin methods that do not involve
@@ -21,10 +26,17 @@ use istring::IString;
 pub struct LocalEnv {
     paths:          Vec<Directory>,
     commands:       HashMap<IString, Command>,
-    targets:        HashMap<IString, NodeP>,
+    targets:        IndexMap<IString, NodeP>,
     groups:         IndexMap<(IString, IString), NodeP>,
-    hyphenator:     Option<Hyphenator>,
-    symbols:        IndexMap<IString, IString>
+    hyphenator:     Option<(HyphenatorFiles, Shared<Hyphenator>)>,
+    symbols:        IndexMap<IString, IString>,
+    language:       Option<Shared<Language>>,
+    
+    // blocks using this environment are entries of the table of contents
+    toc:            Option<u8>,
+    
+    // shortest fragments hyphenation may leave (left, right)
+    hyphenmin:      Option<(usize, usize)>
 }
 
 pub struct Fields {
@@ -32,6 +44,22 @@ pub struct Fields {
     pub body:   Option<NodeListP>
 }
 impl Fields {
+    pub fn encode(&self, e: &mut Encoder) {
+        for field in [&self.args, &self.body].iter() {
+            match **field {
+                Some(ref list) => e.node(list),
+                None => e.nil()
+            }
+        }
+    }
+    pub fn decode(d: &mut Decoder) -> Result<Fields, DecodeError> {
+        let args = if d.nil()? { None } else { Some(d.list()?) };
+        let body = if d.nil()? { None } else { Some(d.list()?) };
+        Ok(Fields {
+            args:   args,
+            body:   body
+        })
+    }
     pub fn childs(&self, out: &mut Vec<NodeP>) {
         if let Some(ref args) = self.args {
             out.push(args.clone().into());
@@ -47,10 +75,13 @@ impl LocalEnv {
         LocalEnv {
             paths:      vec![],
             commands:   HashMap::new(),
-            targets:    HashMap::new(),
+            targets:    IndexMap::new(),
             groups:     IndexMap::new(),
             hyphenator: None,
-            symbols:    IndexMap::new()
+            symbols:    IndexMap::new(),
+            language:   None,
+            toc:        None,
+            hyphenmin:  None
         }
     }
     pub fn add_command(&mut self, name: &str, cmd: Command) {
@@ -73,15 +104,221 @@ impl LocalEnv {
     pub fn get_target(&self, name: &str) -> Option<&NodeP> {
         self.targets.get(name)
     }
+    pub fn get_group(&self, key: &(IString, IString)) -> Option<&NodeP> {
+        self.groups.get(key)
+    }
     pub fn targets<'a>(&'a self) -> impl Iterator<Item=(&'a IString, &'a NodeP)> {
         self.targets.iter()
     }
-    pub fn set_hyphenator(&mut self, hyphenator: Hyphenator) {
-        self.hyphenator = Some(hyphenator);
+    /// `files` are those the hyphenator was loaded from
+    pub fn set_hyphenator(&mut self, files: HyphenatorFiles, hyphenator: Shared<Hyphenator>) {
+        self.hyphenator = Some((files, hyphenator));
     }
     pub fn add_symbol(&mut self, src: &str, dst: &str) {
         self.symbols.insert(src.into(), dst.into());
     }
+    pub fn set_language(&mut self, language: Shared<Language>) {
+        self.language = Some(language);
+    }
+    pub fn set_toc(&mut self, level: u8) {
+        self.toc = Some(level);
+    }
+    /// level in the table of contents, if any
+    pub fn toc_level(&self) -> Option<u8> {
+        self.toc
+    }
+    pub fn set_hyphenmin(&mut self, left: usize, right: usize) {
+        self.hyphenmin = Some((left, right));
+    }
+    
+    /// Search paths are not encoded. They depend on the machine.
+    pub fn encode(&self, e: &mut Encoder) {
+        let mut commands: Vec<&IString> = self.commands.keys().collect();
+        commands.sort();
+        e.array(commands.len());
+        for name in commands {
+            e.str(name);
+        }
+        
+        e.array(self.targets.len());
+        for (name, node) in self.targets.iter() {
+            e.str(name);
+            e.node(node);
+        }
+        
+        e.array(self.groups.len());
+        for (&(ref opening, ref closing), node) in self.groups.iter() {
+            e.str(opening);
+            e.str(closing);
+            e.node(node);
+        }
+        
+        match self.hyphenator {
+            Some((ref files, _)) => e.hyphenator(files),
+            None => e.nil()
+        }
+        
+        e.array(self.symbols.len());
+        for (src, dst) in self.symbols.iter() {
+            e.str(src);
+            e.str(dst);
+        }
+        
+        match self.language {
+            Some(ref language) => e.str(language.name()),
+            None => e.nil()
+        }
+        
+        match self.toc {
+            Some(level) => e.uint(level as u64),
+            None => e.nil()
+        }
+        
+        match self.hyphenmin {
+            Some((left, right)) => {
+                e.array(2);
+                e.uint(left as u64);
+                e.uint(right as u64);
+            },
+            None => e.nil()
+        }
+    }
+    
+    pub fn decode(d: &mut Decoder) -> Result<LocalEnv, DecodeError> {
+        use commands;
+        
+        let mut env = LocalEnv::new();
+        
+        let n = d.array()?;
+        if n > 0 {
+            let mut known = LocalEnv::new();
+            commands::register(&mut known);
+            for _ in 0 .. n {
+                let name = d.str()?;
+                match known.commands.get(&name) {
+                    Some(&cmd) => env.add_command(&name, cmd),
+                    None => return Err(DecodeError::Invalid("command"))
+                }
+            }
+        }
+        
+        for _ in 0 .. d.array()? {
+            let name = d.str()?;
+            env.add_target(name, d.node()?);
+        }
+        
+        for _ in 0 .. d.array()? {
+            let opening = d.str()?;
+            let closing = d.str()?;
+            env.add_group(opening, closing, d.node()?);
+        }
+        
+        if !d.nil()? {
+            let (files, hyphenator) = d.hyphenator()?;
+            env.set_hyphenator(files, hyphenator);
+        }
+        
+        for _ in 0 .. d.array()? {
+            let src = d.str()?;
+            let dst = d.str()?;
+            env.add_symbol(&src, &dst);
+        }
+        
+        if !d.nil()? {
+            match Language::by_name(&d.str()?) {
+                Some(language) => env.set_language(Shared::new(language)),
+                None => return Err(DecodeError::Invalid("language"))
+            }
+        }
+        
+        if !d.nil()? {
+            env.set_toc(d.uint()? as u8);
+        }
+        
+        if !d.nil()? {
+            if d.array()? != 2 {
+                return Err(DecodeError::Invalid("hyphenmin"));
+            }
+            let left = d.uint()? as usize;
+            let right = d.uint()? as usize;
+            env.set_hyphenmin(left, right);
+        }
+        
+        Ok(env)
+    }
+}
+
+impl LocalEnv {
+    pub fn to_json(&self, j: &mut JsonWriter) -> JsonEnv {
+        let mut commands: Vec<String> = self.commands.keys().map(|k| k.to_string()).collect();
+        commands.sort();
+        let files = self.hyphenator.as_ref().map(|&(ref files, _)| files);
+        
+        JsonEnv {
+            commands:   commands,
+            targets:    self.targets.iter().map(|(name, node)| JsonTarget {
+                name:   name.to_string(),
+                node:   j.shared(node)
+            }).collect(),
+            groups:     self.groups.iter().map(|(&(ref opening, ref closing), node)| JsonGroup {
+                opening:    opening.to_string(),
+                closing:    closing.to_string(),
+                node:       j.shared(node)
+            }).collect(),
+            hyphenator: files.and_then(|f| f.dictionary.as_ref()).map(|d| d.to_string()),
+            patterns:   files.and_then(|f| f.patterns.as_ref()).map(|p| p.to_string()),
+            symbols:    self.symbols.iter().map(|(s, d)| (s.to_string(), d.to_string())).collect(),
+            language:   self.language.as_ref().map(|l| l.name().to_string()),
+            toc:        self.toc,
+            hyphenmin:  self.hyphenmin
+        }
+    }
+    
+    pub fn from_json(r: &mut JsonReader, json: JsonEnv) -> Result<LocalEnv, LoomError> {
+        use commands;
+        
+        let mut env = LocalEnv::new();
+        let hyphenator_files = json.hyphenator_files();
+        
+        let mut known = LocalEnv::new();
+        commands::register(&mut known);
+        for name in json.commands {
+            match known.commands.get(&*name) {
+                Some(&cmd) => env.add_command(&name, cmd),
+                None => return Err(LoomError::MissingItem(name.into()))
+            }
+        }
+        
+        for t in json.targets {
+            let node = r.shared(t.node)?;
+            env.add_target(t.name.into(), node);
+        }
+        for g in json.groups {
+            let node = r.shared(g.node)?;
+            env.add_group(g.opening.into(), g.closing.into(), node);
+        }
+        if let Some(files) = hyphenator_files {
+            let hyphenator = r.hyphenator(&files)?;
+            env.set_hyphenator(files, hyphenator);
+        }
+        for (src, dst) in json.symbols {
+            env.add_symbol(&src, &dst);
+        }
+        if let Some(name) = json.language {
+            match Language::by_name(&name) {
+                Some(language) => env.set_language(Shared::new(language)),
+                None => return Err(LoomError::MissingItem(name.into()))
+            }
+        }
+        if let Some(level) = json.toc {
+            env.set_toc(level);
+        }
+        if let Some((left, right)) = json.hyphenmin {
+            env.set_hyphenmin(left, right);
+        }
+        
+        Ok(env)
+    }
 }
 
 pub struct GraphLink {
@@ -91,13 +328,13 @@ pub struct GraphLink {
 
 #[derive(Clone)]
 pub struct GraphChain {
-    inner: Rc<GraphLink>
+    inner: Shared<GraphLink>
 }
 
 impl GraphChain {
     pub fn root(local: LocalEnv) -> GraphChain {
         GraphChain {
-            inner: Rc::new(GraphLink {
+            inner: Shared::new(GraphLink {
                 parent: None,
                 local:  local
             })
@@ -106,14 +343,14 @@ impl GraphChain {
     
     pub fn link(&self, local: LocalEnv) -> GraphChain {
         GraphChain {
-            inner: Rc::new(GraphLink {
+            inner: Shared::new(GraphLink {
                 parent: Some(self.clone()),
                 local:  local
             })
         }
     }
     pub fn take(self) -> LocalEnv {
-        match Rc::try_unwrap(self.inner) {
+        match Shared::try_unwrap(self.inner) {
             Ok(e) => e.local,
             Err(_) => panic!("refcount > 1")
         }
@@ -142,6 +379,9 @@ impl GraphChain {
     pub fn get_command(&self, name: &str) -> Option<&Command> {
         self.find(|env| env.commands.get(name))
     }
+    pub fn get_language(&self) -> Option<&Language> {
+        self.find(|env| env.language.as_ref().map(|l| &**l))
+    }
     pub fn get_symbol(&self, name: &str) -> Option<&str> {
         match self.find(|env| env.symbols.get(name)) {
             Some(ref s) => Some(s.as_str()),
@@ -157,11 +397,76 @@ impl Deref for GraphChain {
     }
 }
 
+// deeper than this is certainly not intended
+const MAX_EXPANSION_DEPTH: usize = 64;
+
+/// A macro being expanded. See `LayoutChain::expand`.
+pub struct Expansion<'a> {
+    parent: Option<&'a Expansion<'a>>,
+    
+    // the fields where the macro was invoked, see `LayoutChain::outside`
+    fields: Option<&'a Fields>,
+    name:   &'a str,
+    
+    // address of the definition
+    target: usize,
+    depth:  usize
+}
+impl<'a> Expansion<'a> {
+    /// Expand `target`, invoked as `name` in `caller`.
+    /// If that would not end, the chain of macros is returned instead.
+    ///
+    /// What is passed to the macro is laid out outside of it again,
+    /// so only a macro that ends up invoking itself counts.
+    pub fn new(caller: &LayoutChain<'a>, name: &'a str, target: &NodeP)
+     -> Result<Expansion<'a>, String>
+    {
+        let parent = caller.expansion;
+        let exp = Expansion {
+            parent: parent,
+            fields: caller.fields,
+            name:   name,
+            target: target.addr(),
+            depth:  parent.map(|p| p.depth + 1).unwrap_or(0)
+        };
+        
+        let mut recursive = exp.depth >= MAX_EXPANSION_DEPTH;
+        let mut p = parent;
+        while let Some(e) = p {
+            recursive |= e.target == exp.target;
+            p = e.parent;
+        }
+        
+        match recursive {
+            false => Ok(exp),
+            true => Err(exp.names().join(" → "))
+        }
+    }
+    
+    /// the names of the macros, starting with the outermost one
+    pub fn names(&self) -> Vec<&'a str> {
+        let mut names = vec![self.name];
+        let mut p = self.parent;
+        while let Some(e) = p {
+            names.push(e.name);
+            p = e.parent;
+        }
+        names.reverse();
+        names
+    }
+    
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+}
+
 #[derive(Clone)]
 pub struct LayoutChain<'a> {
-    parent: Option<&'a LayoutChain<'a>>,
-    local:  &'a LocalEnv,
-    fields: Option<&'a Fields>
+    parent:     Option<&'a LayoutChain<'a>>,
+    local:      &'a LocalEnv,
+    fields:     Option<&'a Fields>,
+    marks:      Option<&'a Marks>,
+    expansion:  Option<&'a Expansion<'a>>
 }
 impl<'a> Deref for LayoutChain<'a> {
     type Target = LocalEnv;
@@ -172,9 +477,19 @@ impl<'a> Deref for LayoutChain<'a> {
 impl<'a> LayoutChain<'a> {
     pub fn root(env: &LocalEnv) -> LayoutChain {
         LayoutChain {
-            parent: None,
-            local:  env,
-            fields: None
+            parent:     None,
+            local:      env,
+            fields:     None,
+            marks:      None,
+            expansion:  None
+        }
+    }
+    
+    /// make the marks of a previous layout pass available
+    pub fn with_marks(self, marks: &'a Marks) -> LayoutChain<'a> {
+        LayoutChain {
+            marks:      Some(marks),
+            ..          self
         }
     }
 
@@ -182,9 +497,11 @@ impl<'a> LayoutChain<'a> {
         match node.env() {
             Some(local) => {
                 LayoutChain {
-                    parent: Some(self),
-                    local:  local,
-                    fields: node.fields().or(self.fields)
+                    parent:     Some(self),
+                    local:      local,
+                    fields:     node.fields().or(self.fields),
+                    marks:      self.marks,
+                    expansion:  self.expansion
                 }
             }
             None => self.clone()
@@ -195,9 +512,37 @@ impl<'a> LayoutChain<'a> {
     where 'a: 'b
     {
         LayoutChain {
-            parent: self.parent,
-            local:  self.local,
-            fields: fields
+            parent:     self.parent,
+            local:      self.local,
+            fields:     fields,
+            marks:      self.marks,
+            expansion:  self.expansion
+        }
+    }
+    
+    /// the macros being expanded
+    pub fn expansion(&self) -> Option<&'a Expansion<'a>> {
+        self.expansion
+    }
+    
+    /// lay out the body of a macro within this one
+    pub fn expand<'b>(&'b self, exp: &'b Expansion<'b>) -> LayoutChain<'b> where 'a: 'b {
+        LayoutChain {
+            expansion:  Some(exp),
+            ..          self.clone()
+        }
+    }
+    
+    /// Back where the innermost macro was invoked,
+    /// for what was passed to it (`$body`, `$args` …).
+    pub fn outside(&self) -> LayoutChain<'a> {
+        match self.expansion {
+            Some(exp) => LayoutChain {
+                fields:     exp.fields,
+                expansion:  exp.parent,
+                ..          self.clone()
+            },
+            None => self.clone()
         }
     }
 
@@ -213,55 +558,312 @@ impl<'a> LayoutChain<'a> {
         }
     }
     
+    // `link` passes them on already
     pub fn fields(&self) -> Option<&Fields> {
-        self.find(|c| c.fields)
+        self.fields
+    }
+    
+    /// None in the first layout pass
+    pub fn marks(&self) -> Option<&Marks> {
+        self.marks
     }
     
     pub fn hyphenator(&self) -> Option<&Hyphenator> {
-        self.find(|c| c.hyphenator.as_ref())
+        self.find(|c| c.hyphenator.as_ref().map(|&(_, ref h)| &**h))
     }
+    /// shortest fragments (left, right) hyphenation may leave, see `!hyphenmin`
+    pub fn hyphenmin(&self) -> (usize, usize) {
+        self.find(|c| c.hyphenmin.as_ref()).cloned().unwrap_or((2, 3))
+    }
+    
+    /// break points of `text`, which may have punctuation attached
+    fn hyphens(&self, text: &str) -> Vec<Hyphen> {
+        let is_letter = |c: char| c.is_alphanumeric();
+        let start = text.len() - text.trim_left_matches(|c: char| !is_letter(c)).len();
+        let word = text[start ..].trim_right_matches(|c: char| !is_letter(c));
+        let offset = text[.. start].chars().count();
+        let len = word.chars().count();
+        
+        // only break after a hyphen that is there already
+        if word.contains(EXPLICIT_HYPHENS) {
+            return word.chars().enumerate()
+                .filter(|&(n, c)| EXPLICIT_HYPHENS.contains(&c) && n > 0 && n + 1 < len)
+                .map(|(n, _)| Hyphen::weighted(offset + n + 1, Hyphen::PREFERRED))
+                .collect();
+        }
+        
+        // acronyms
+        if len > 1 && word.chars().all(|c| !c.is_lowercase()) {
+            return vec![];
+        }
+        
+        let (start, end) = stem(word);
+        let part = &word[start .. end];
+        let offset = offset + word[.. start].chars().count();
+        let len = part.chars().count();
+        
+        let (left_min, right_min) = self.hyphenmin();
+        match self.hyphenator().and_then(|h| h.get(part)) {
+            Some(points) => points.iter()
+                .filter(|p| p.pos() >= left_min.max(1) && p.pos() + right_min.max(1) <= len)
+                .map(|p| Hyphen::weighted(offset + p.pos(), p.weight()))
+                .collect(),
+            None => vec![]
+        }
+    }
+    
+    /// Lay out a word that may be hyphenated.
+    /// Soft hyphens in the word take precedence over the hyphenator.
     pub fn hyphenate(&self, w: &mut Writer, word: Atom) {
-        if let Some(hyphenator) = self.hyphenator() {
-            if let Some(points) = hyphenator.get(word.text) {
-                w.branch(&mut |b| {
-                    b.add(&mut |w: &mut Writer| w.word(word) );
-                        
-                    for p in points.iter() {
-                        let (left, right) = p.apply(word.text);
-                        b.add(&mut |w: &mut Writer| {
-                            w.word(Atom {
-                                left:   word.left,
-                                right:  Glue::None,
-                                text:   left
-                            });
-                            w.punctuation(Atom {
-                                left:   Glue::None,
-                                right:  Glue::newline(),
-                                text:   "-"
-                            });
-                            w.word(Atom {
-                                left:   Glue::newline(),
-                                right:  word.right,
-                                text:   right
-                            });
+        let stripped;
+        let (text, points, splittable) = match word.text.contains(SOFT_HYPHEN) {
+            true => {
+                let (s, points) = soft_hyphens(word.text);
+                stripped = s;
+                (stripped.as_str(), points, false)
+            },
+            false => (word.text, self.hyphens(word.text), true)
+        };
+        let word = Atom {
+            text:   text,
+            ..      word
+        };
+        
+        if points.len() == 0 {
+            if splittable && text.chars().count() >= SPLIT_LENGTH {
+                self.split(w, word);
+            } else {
+                w.word(word);
+            }
+            return;
+        }
+        
+        w.branch(&mut |b| {
+            b.add(&mut |w: &mut Writer| w.word(word) );
+                
+            for p in points.iter() {
+                let (left, right) = p.apply(word.text);
+                let explicit = left.ends_with(EXPLICIT_HYPHENS);
+                b.add(&mut |w: &mut Writer| {
+                    w.penalty(p.penalty(), true);
+                    w.word(Atom {
+                        left:   word.left,
+                        right:  if explicit { Glue::newline() } else { Glue::None },
+                        text:   left
+                    });
+                    if !explicit {
+                        w.punctuation(Atom {
+                            left:   Glue::None,
+                            right:  Glue::newline(),
+                            text:   "-"
                         });
                     }
+                    w.word(Atom {
+                        left:   Glue::newline(),
+                        right:  word.right,
+                        text:   right
+                    });
                 });
-                return;
             }
-        }
+        });
+    }
+    
+    // Break the word anywhere, without a hyphen. Only taken if nothing else fits.
+    fn split(&self, w: &mut Writer, word: Atom) {
+        let (left_min, right_min) = self.hyphenmin();
+        let len = word.text.chars().count();
         
-        // fallback
-        w.word(word);
+        w.branch(&mut |b| {
+            b.add(&mut |w: &mut Writer| w.word(word) );
+            
+            for (pos, (n, _)) in word.text.char_indices().enumerate() {
+                if pos < left_min.max(1) || pos + right_min.max(1) > len {
+                    continue;
+                }
+                let (left, right) = word.text.split_at(n);
+                b.add(&mut |w: &mut Writer| {
+                    w.last_resort();
+                    w.penalty(SPLIT_PENALTY, false);
+                    w.word(Atom {
+                        left:   word.left,
+                        right:  Glue::newline(),
+                        text:   left
+                    });
+                    w.word(Atom {
+                        left:   Glue::newline(),
+                        right:  word.right,
+                        text:   right
+                    });
+                });
+            }
+        });
+    }
+}
+
+// The part of `word` that is hyphenated, as a byte range.
+// An elided article in front (l’, qu’) and a contraction at the end
+// (’s, n’t, ’ll) are left alone, an apostrophe in between stays in.
+fn stem(word: &str) -> (usize, usize) {
+    let mut start = 0;
+    let mut end = word.len();
+    
+    if let Some(n) = word.find(APOSTROPHES) {
+        if word[.. n].chars().count() <= 2 && word[n ..].chars().count() > 3 {
+            start = n + word[n ..].chars().next().map(|c| c.len_utf8()).unwrap_or(0);
+        }
+    }
+    if let Some(n) = word[start ..].rfind(APOSTROPHES).map(|n| start + n) {
+        let suffix = word[n ..].chars().skip(1).collect::<String>();
+        if suffix.chars().count() <= 2 {
+            end = n;
+            if suffix == "t" && word[start .. end].ends_with('n') {
+                end -= 1;
+            }
+        }
+    }
+    (start, end.max(start))
+}
+
+// The text without soft hyphens, and a break point for each of them.
+// A soft hyphen in front of the word forbids breaking it.
+fn soft_hyphens(text: &str) -> (String, Vec<Hyphen>) {
+    let mut out = String::with_capacity(text.len());
+    let mut points = vec![];
+    let mut forbidden = false;
+    let mut len = 0;
+    
+    for c in text.chars() {
+        if c == SOFT_HYPHEN {
+            match len {
+                0 => forbidden = true,
+                n => points.push(Hyphen::at(n))
+            }
+        } else {
+            out.push(c);
+            len += 1;
+        }
+    }
+    
+    if forbidden {
+        points.clear();
+    }
+    points.retain(|p| p.pos() < len);
+    (out, points)
+}
+
+const EXPLICIT_HYPHENS: &[char] = &['-', '\u{2010}'];
+const APOSTROPHES: &[char] = &['\'', '\u{2019}', '\u{2BC}'];
+
+// Words this long without break points may be split anywhere, as a last resort.
+const SPLIT_LENGTH: usize = 16;
+const SPLIT_PENALTY: f32 = 100.0;
+
+/// Scopes of the resolution pass.
+/// Unlike the `GraphChain`, it contains everything a block can see,
+/// including definitions that come after it.
+pub struct Scope<'a> {
+    parent: Option<&'a Scope<'a>>,
+    local:  &'a LocalEnv
+}
+impl<'a> Scope<'a> {
+    pub fn root(env: &LocalEnv) -> Scope {
+        Scope {
+            parent: None,
+            local:  env
+        }
+    }
+    
+    pub fn link<'b, N: Node + ?Sized>(&'b self, node: &'b N) -> Scope<'b> where 'a: 'b {
+        match node.env() {
+            Some(local) => Scope {
+                parent: Some(self),
+                local:  local
+            },
+            None => Scope {
+                parent: self.parent,
+                local:  self.local
+            }
+        }
+    }
+    
+    pub fn get_target(&self, name: &str) -> Option<&'a NodeP> {
+        match self.local.get_target(name) {
+            Some(n) => Some(n),
+            None => self.parent.and_then(|p| p.get_target(name))
+        }
+    }
+    pub fn get_group(&self, key: &(IString, IString)) -> Option<&'a NodeP> {
+        match self.local.get_group(key) {
+            Some(n) => Some(n),
+            None => self.parent.and_then(|p| p.get_group(key))
+        }
+    }
+}
+
+/// Resolve everything below `root` that is still unresolved,
+/// and return the nodes where that failed.
+pub fn resolve(root: &NodeP, env: &LocalEnv) -> Vec<NodeP> {
+    let mut seen = HashSet::new();
+    let mut unresolved = vec![];
+    resolve_node(root, &Scope::root(env), &mut seen, &mut unresolved);
+    unresolved
+}
+
+fn resolve_node(node: &NodeP, scope: &Scope, seen: &mut HashSet<usize>, unresolved: &mut Vec<NodeP>) {
+    if !seen.insert(node.addr()) {
+        return;
+    }
+    
+    let scope = scope.link(node);
+    let known = node.target().is_some();
+    if node.resolve(&scope) {
+        if !known {
+            if let Some(target) = node.target() {
+                target.add_ref(node);
+            }
+        }
+    } else {
+        unresolved.push(node.clone());
+    }
+    
+    let mut childs = vec![];
+    node.childs(&mut childs);
+    for c in childs.iter() {
+        resolve_node(c, &scope, seen, unresolved);
     }
 }
 
 pub fn prepare_graph(_io: &Io) -> GraphChain {
     use commands;
+    use nodes;
     
     let mut e = LocalEnv::new();
     
     commands::register(&mut e);
+    nodes::register(&mut e);
     
     GraphChain::root(e)
 }
+
+#[test]
+fn test_stem() {
+    let part = |w: &'static str| {
+        let (start, end) = stem(w);
+        &w[start .. end]
+    };
+    assert_eq!(part("l'homme"), "homme");
+    assert_eq!(part("l’homme"), "homme");
+    assert_eq!(part("children's"), "children");
+    assert_eq!(part("don't"), "do");
+    assert_eq!(part("it's"), "it");
+    assert_eq!(part("aujourd'hui"), "aujourd'hui");
+    assert_eq!(part("well-known"), "well-known");
+}
+
+#[test]
+fn test_explicit_hyphens() {
+    let env = LocalEnv::new();
+    let chain = LayoutChain::root(&env);
+    assert_eq!(chain.hyphens("well-known"), vec![Hyphen::weighted(5, Hyphen::PREFERRED)]);
+    assert_eq!(chain.hyphens("(well-known)."), vec![Hyphen::weighted(6, Hyphen::PREFERRED)]);
+}
@@ -1,18 +1,57 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::io;
 use std::ops;
 use std::fs;
+use std::slice;
+use std::thread;
 use std::collections::BTreeMap;
+use std::fmt;
 use fst::{Map, MapBuilder, Error};
+use futures::Future;
+use futures::sync::oneshot;
+use patterns::Patterns;
+use istring::IString;
 
-#[derive(Clone)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct Hyphen {
-    pos:    u8
+    pos:    u32,
+    weight: u8
 }
 impl Hyphen {
+    /// weight of break points that don't say otherwise
+    pub const NORMAL: u8 = 8;
+    
+    /// weight of break points that should be used first, like `=` in hyphen lists
+    pub const PREFERRED: u8 = 12;
+    
+    /// the highest weight that can be stored
+    pub const MAX_WEIGHT: u8 = 15;
+    
     pub fn at(pos: usize) -> Hyphen {
-        assert!(pos < 256);
-        Hyphen { pos: pos as u8 }
+        Hyphen::weighted(pos, Hyphen::NORMAL)
+    }
+    
+    /// `weight` goes from 0 to `MAX_WEIGHT`. Higher is better.
+    pub fn weighted(pos: usize, weight: u8) -> Hyphen {
+        Hyphen {
+            pos:    pos as u32,
+            weight: weight.min(Hyphen::MAX_WEIGHT)
+        }
+    }
+    
+    /// number of chars before the break
+    pub fn pos(&self) -> usize {
+        self.pos as usize
+    }
+    
+    pub fn weight(&self) -> u8 {
+        self.weight
+    }
+    
+    /// Subtracted from the score of a line that ends here.
+    /// Any hyphen is worse than none, preferred ones less so.
+    pub fn penalty(&self) -> f32 {
+        0.2 + 0.1 * (Hyphen::MAX_WEIGHT - self.weight) as f32
     }
     
     /// position specifies the char number not the byte count.
@@ -33,52 +72,145 @@ impl Hyphen {
     }
 }
 
-#[derive(Default, Clone)]
+#[derive(Default, Clone, Debug, PartialEq)]
 pub struct Hyphens {
-    data:   u64
+    points: Vec<Hyphen>
 }
 impl ops::Shl<Hyphen> for Hyphens {
     type Output = Hyphens;
-    fn shl(self, rhs: Hyphen) -> Hyphens {
-        assert!(self.data < 1<<56);
-        Hyphens { data : self.data << 8 | rhs.pos as u64 }
+    fn shl(mut self, rhs: Hyphen) -> Hyphens {
+        self.points.push(rhs);
+        self
     }
 }
 impl ops::ShlAssign<Hyphen> for Hyphens {
     fn shl_assign(&mut self, rhs: Hyphen) {
-        assert!(self.data < 1<<56);
-        self.data = self.data << 8 | rhs.pos as u64;
+        self.points.push(rhs);
     }
 }
 impl Hyphens {
     pub fn iter(&self) -> HyphenIter {
-        HyphenIter { data: self.data }
+        HyphenIter { inner: self.points.iter() }
     }
     pub fn len(&self) -> usize {
-        self.iter().count()
+        self.points.len()
+    }
+    
+    // Values in the FST:
+    //
+    // Up to seven points of normal weight are stored like they always were,
+    // one byte per position, the last one in the lowest byte.
+    //
+    // Anything else sets the top bit and stores the number of extra keys.
+    // `word\0000`, `word\0001`, … hold four points each,
+    // 12 bits position and 4 bits weight. Position 0 marks the end.
+    fn encode(&self, word: &str) -> io::Result<Vec<(String, u64)>> {
+        let simple = self.points.len() <= 7 && self.points.iter()
+            .all(|h| h.weight == Hyphen::NORMAL && h.pos > 0 && h.pos < 256);
+        
+        if simple {
+            let data = self.points.iter().fold(0, |data, h| data << 8 | h.pos as u64);
+            return Ok(vec![(word.to_owned(), data)]);
+        }
+        
+        if let Some(h) = self.points.iter().find(|h| h.pos >= 1 << 12) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                format!("{}: a break at position {} can't be stored", word, h.pos)
+            ));
+        }
+        let packed: Vec<u64> = self.points.iter()
+            .filter(|h| h.pos > 0)
+            .map(|h| (h.pos as u64) << 4 | h.weight as u64)
+            .collect();
+        
+        let mut out = vec![(word.to_owned(), EXTENDED | ((packed.len() + 3) / 4) as u64)];
+        for (n, chunk) in packed.chunks(4).enumerate() {
+            let data = chunk.iter().enumerate().fold(0, |data, (i, &p)| data | p << (16 * i));
+            out.push((chunk_key(word, n), data));
+        }
+        Ok(out)
+    }
+    
+    fn decode<F>(value: u64, chunk: F) -> Hyphens where F: Fn(usize) -> Option<u64> {
+        let mut points = vec![];
+        
+        if value & EXTENDED == 0 {
+            let mut data = value;
+            while data > 0 {
+                points.push(Hyphen::at((data & 0xFF) as usize));
+                data >>= 8;
+            }
+            points.reverse();
+        } else {
+            for n in 0 .. (value & !EXTENDED) as usize {
+                let mut data = chunk(n).unwrap_or(0);
+                while data & 0xFFFF != 0 {
+                    points.push(Hyphen::weighted((data >> 4 & 0xFFF) as usize, (data & 0xF) as u8));
+                    data >>= 16;
+                }
+            }
+        }
+        Hyphens { points: points }
     }
 }
 
-pub struct HyphenIter {
-    data:   u64
+// write to a temporary file first, so readers never see half a dictionary
+fn write_file(path: &Path, data: &[u8]) -> io::Result<()> {
+    use std::io::Write;
+    
+    let tmp = path.with_extension("fst.part");
+    {
+        let mut file = io::BufWriter::new(fs::File::create(&tmp)?);
+        file.write_all(data)?;
+        file.flush()?;
+    }
+    fs::rename(tmp, path)
 }
-impl Iterator for HyphenIter {
+
+const EXTENDED: u64 = 1 << 63;
+
+// sorts right after `word` and before any other word starting with it
+fn chunk_key(word: &str, n: usize) -> String {
+    format!("{}\0{:03}", word, n)
+}
+
+pub struct HyphenIter<'a> {
+    inner:  slice::Iter<'a, Hyphen>
+}
+impl<'a> Iterator for HyphenIter<'a> {
     type Item = Hyphen;
     
     fn next(&mut self) -> Option<Hyphen> {
-        if self.data > 0 {
-            let h = Hyphen { pos: self.data as u8 };
-            self.data >>= 8;
-            Some(h)
-        } else {
-            None
+        self.inner.next().cloned()
+    }
+}
+
+/// The files in the data directory a `Hyphenator` is loaded from.
+/// Documents refer to it by them.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct HyphenatorFiles {
+    pub dictionary: Option<IString>,
+    
+    /// a `hyph-*.pat` pattern file, for words missing in the dictionary
+    pub patterns:   Option<IString>
+}
+impl fmt::Display for HyphenatorFiles {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.dictionary, &self.patterns) {
+            (&Some(ref d), &Some(ref p)) => write!(f, "{} and {}", d, p),
+            (&Some(ref d), &None) => write!(f, "{}", d),
+            (&None, &Some(ref p)) => write!(f, "{}", p),
+            (&None, &None) => write!(f, "no hyphenation")
         }
     }
 }
 
 pub struct Hyphenator {
     map:        Map,
-    changes:    BTreeMap<String, Hyphens>
+    changes:    BTreeMap<String, Hyphens>,
+    
+    // for words that are not in the dictionary
+    patterns:   Option<Patterns>
 }
 
 struct Entry {
@@ -90,22 +222,58 @@ impl Hyphenator {
     pub fn add(&mut self, word: String, hyphens: Hyphens) {
         self.changes.insert(word, hyphens);
     }
+    pub fn set_patterns(&mut self, patterns: Patterns) {
+        self.patterns = Some(patterns);
+    }
     pub fn get(&self, word: &str) -> Option<Hyphens> {
         let lower = word.to_lowercase();
         match self.changes.get(&lower) {
             Some(h) => Some(h.clone()),
             None => match self.map.get(&lower) {
-                Some(v) => Some(Hyphens { data: v }),
-                None => None
+                Some(v) => Some(self.decode(&lower, v)),
+                None => self.patterns.as_ref()
+                    .map(|p| p.hyphenate(&lower))
+                    .filter(|h| h.len() > 0)
             }
         }
     }
     
+    fn decode(&self, word: &str, value: u64) -> Hyphens {
+        Hyphens::decode(value, |n| self.map.get(chunk_key(word, n)))
+    }
+    
+    /// all words of the dictionary, including the changes
+    pub fn entries(&self) -> BTreeMap<String, Hyphens> {
+        use std::str;
+        use fst::Streamer;
+        
+        let mut entries = BTreeMap::new();
+        let mut stream = self.map.stream();
+        while let Some((key, val)) = stream.next() {
+            match str::from_utf8(key) {
+                Ok(word) if !word.contains('\0') => {
+                    entries.insert(word.to_owned(), self.decode(word, val));
+                },
+                _ => {}
+            }
+        }
+        for (word, hyphens) in self.changes.iter() {
+            entries.insert(word.clone(), hyphens.clone());
+        }
+        entries
+    }
+    
+    /// Map the dictionary into memory instead of reading it.
+    /// Processes using the same file share its pages.
     #[cfg(feature = "mmap")]
-    pub fn load_file(file: Path) -> Result<Hyphenator, Error> {
+    pub fn load_file(path: &Path) -> Result<Hyphenator, Error> {
+        // `save` replaces the file instead of writing to it,
+        // so the mapping stays valid.
+        let map = unsafe { Map::from_path(path)? };
         Ok(Hyphenator {
-            map: Map::from_path(p),
-            changes: BTreeMap::new()
+            map: map,
+            changes: BTreeMap::new(),
+            patterns: None
         })
     }
     
@@ -113,7 +281,8 @@ impl Hyphenator {
         match Map::from_bytes(data) {
             Ok(map) => Ok(Hyphenator {
                 map: map,
-                changes: BTreeMap::new()
+                changes: BTreeMap::new(),
+                patterns: None
             }),
             Err(e) => Err(e)
         }
@@ -123,23 +292,39 @@ impl Hyphenator {
         use std::iter;
         Hyphenator {
             map: Map::from_iter(iter::empty::<(&str, u64)>()).unwrap(),
-            changes: BTreeMap::new()
+            changes: BTreeMap::new(),
+            patterns: None
         }
     }
     
-    // TODO: Asyncify
     pub fn save(&self, path: &Path) {
-        let fst_tmp = path.with_extension("fst.part");
-        
         if self.changes.len() == 0 {
             return; // nothing to do
         }
         
-        let mut builder = MapBuilder::new(
-            io::BufWriter::new(
-                fs::File::create(&fst_tmp).unwrap()
-            )
-        ).unwrap();
+        self.to_bytes().and_then(|data| write_file(path, &data)).expect("could not write the dictionary");
+    }
+    
+    /// Like `save`, but the dictionary is merged and written on another thread.
+    /// It is handed over to that thread.
+    /// Unlike `save`, the file is written even without changes.
+    pub fn save_async(self, path: PathBuf) -> Box<Future<Item=(), Error=io::Error>> {
+        let (tx, rx) = oneshot::channel();
+        thread::spawn(move || {
+            let _ = tx.send(self.to_bytes().and_then(|data| write_file(&path, &data)));
+        });
+        
+        box rx.then(|r| match r {
+            Ok(r) => r,
+            Err(_) => Err(io::Error::new(io::ErrorKind::Other, "the writing thread died"))
+        })
+    }
+    
+    /// the dictionary including the changes, as FST data
+    ///
+    /// Fails if a word has a break point that can't be stored.
+    pub fn to_bytes(&self) -> io::Result<Vec<u8>> {
+        let mut builder = MapBuilder::memory();
         
         {
             let mut a_iter = self.map.stream();
@@ -150,10 +335,20 @@ impl Hyphenator {
             let mut next_a = || {
                 use std::str;
                 use fst::Streamer;
-                a_iter.next().map(|(key, val)| { Entry {
-                        key: str::from_utf8(key).unwrap().to_owned(),
-                        value: Hyphens { data: val }
-                } })
+                while let Some((key, val)) = a_iter.next() {
+                    let key = str::from_utf8(key).unwrap().to_owned();
+                    
+                    // extra keys are written again with their word
+                    if key.contains('\0') {
+                        continue;
+                    }
+                    let value = self.decode(&key, val);
+                    return Some(Entry {
+                        key: key,
+                        value: value
+                    });
+                }
+                None
             };
             let mut next_b = || {
                 b_iter.next()
@@ -162,8 +357,11 @@ impl Hyphenator {
                     value: value.clone()
                 } } )
             };
-            let mut insert = |e: &Entry| {
-                builder.insert(e.key.as_str().as_bytes(), e.value.data).expect("out of order");
+            let mut insert = |e: &Entry| -> io::Result<()> {
+                for (key, value) in e.value.encode(&e.key)? {
+                    builder.insert(key.as_bytes(), value).expect("out of order");
+                }
+                Ok(())
             };
             
             let mut o_a: Option<Entry> = next_a();
@@ -181,24 +379,24 @@ impl Hyphenator {
                 let advance = match (&o_a, &o_b) {
                     (&Some(ref a), &Some(ref b)) => match a.key.cmp(&b.key) {
                         Ordering::Less => {
-                            insert(a);
+                            insert(a)?;
                             Advance::A
                         },
                         Ordering::Equal => {
-                            insert(b);
+                            insert(b)?;
                             Advance::AB
                         },
                         Ordering::Greater => {
-                            insert(b);
+                            insert(b)?;
                             Advance::B
                         }
                     },
                     (&Some(ref a), &None) => {
-                        insert(a);
+                        insert(a)?;
                         Advance::A
                     },
                     (&None, &Some(ref b)) => {
-                        insert(b);
+                        insert(b)?;
                         Advance::B
                     },
                     (&None, &None) => break
@@ -218,35 +416,79 @@ impl Hyphenator {
                 }
             }
         }
-        builder.finish().unwrap();
-        
-        fs::rename(fst_tmp, path).expect("could not rename tempfile");
+        Ok(builder.into_inner().unwrap())
     }
     
+    /// Add the words of a list, one per line.
+    /// `|` marks a break point, `=` a preferred one: `Haus=tür|klin|ke`
     // TODO: Asyncify
-    pub fn add_hyphenlist(&mut self, path: &Path) {
+    pub fn add_hyphenlist(&mut self, path: &Path) -> io::Result<()> {
         use std::io::BufRead;
         let reader = io::BufReader::new(
-            fs::File::open(path).unwrap()
+            fs::File::open(path)?
         );
         
         for line in reader.lines() {
             let mut hyphens = Hyphens::default();
             let mut off = 0;
-            let word: String = line.unwrap()
+            let word: String = line?
             .chars()
             .enumerate()
             .filter_map(|(pos, c)|
-                if c == '|' {
-                    hyphens <<= Hyphen::at(pos-off);
-                    off += 1;
-                    None
-                } else {
-                    Some(c)
+                match c {
+                    '|' => {
+                        hyphens <<= Hyphen::at(pos-off);
+                        off += 1;
+                        None
+                    },
+                    '=' => {
+                        hyphens <<= Hyphen::weighted(pos-off, Hyphen::PREFERRED);
+                        off += 1;
+                        None
+                    },
+                    c => Some(c)
                 }
             ).collect();
             
             self.add(word, hyphens);
         }
+        Ok(())
     }
 }
+
+#[test]
+fn test_encoding() {
+    let mut long = Hyphens::default();
+    for pos in 1 .. 20 {
+        long <<= Hyphen::weighted(pos * 15, (pos % 16) as u8);
+    }
+    let short = Hyphens::default() << Hyphen::at(2) << Hyphen::at(6);
+    
+    let mut entries = short.encode("hyphenation").unwrap();
+    entries.extend(long.encode("pneumonoultramicroscopicsilicovolcanoconiosis").unwrap());
+    entries.sort();
+    
+    // the old format
+    assert_eq!(entries[0], ("hyphenation".to_owned(), 2 << 8 | 6));
+    
+    let mut builder = MapBuilder::memory();
+    for (key, value) in entries {
+        builder.insert(key, value).unwrap();
+    }
+    let h = Hyphenator::load(builder.into_inner().unwrap()).unwrap();
+    
+    assert_eq!(h.get("Hyphenation"), Some(short));
+    assert_eq!(h.get("pneumonoultramicroscopicsilicovolcanoconiosis"), Some(long));
+    assert_eq!(h.entries().len(), 2);
+}
+
+#[test]
+fn test_encoding_long_word() {
+    let word: String = ::std::iter::repeat('a').take(5000).collect();
+    let hyphens = Hyphens::default() << Hyphen::at(2) << Hyphen::at(4500);
+    assert!(hyphens.encode(&word).is_err());
+    
+    let mut h = Hyphenator::empty();
+    h.add(word, hyphens);
+    assert!(h.to_bytes().is_err());
+}
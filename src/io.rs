@@ -1,20 +1,31 @@
 #![allow(dead_code)]
 
-//use rmp;
-//use rmp_serialize;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::cell::{RefCell, RefMut};
+use std::io::{self, Write};
 use std::rc::Rc;
+use shared::Shared;
 use std::fmt;
-use woot::{IncrementalStamper};
-use document::{Node, NodeP};
-use layout::Writer;
-use environment::{LocalEnv, LayoutChain, prepare_graph};
+use std::mem;
+use std::slice;
+use woot::{IncrementalStamper, Op};
+use document::{self, Node, NodeP, ListOp};
+use layout::{Writer, Marks, GenericWriter, ColumnLayout};
+use output::Output;
+use units::Length;
+use environment::{self, LocalEnv, LayoutChain, prepare_graph};
 use futures::Future;
 use wheel::prelude::*;
 use config::Config;
 use super::LoomError;
 use nodes::Module;
+use hyphenation::{Hyphenator, HyphenatorFiles};
+use patterns::Patterns;
+use encoding::{self, Encoder, Decoder, DecodeError, StreamItem, StreamState};
+use istring::IString;
+use json::{self, JsonNode, JsonReader, JsonWriter};
+use serde_json;
+use futures::future::{join_all, err, ok};
 
 pub type TypeId = u16;
 pub type DataSize = u32;
@@ -26,9 +37,9 @@ pub struct IoCreate {
     io_ref: Io
 }
 impl IoCreate {
-    pub fn submit(self, data: &[u8]) {
-        // This is not Send -> submit can't be called twice at the same time
-        self.io_ref.borrow_mut().add_data(self.stamp, data);
+    /// record the operation on the list with the given stamp
+    pub fn submit(self, list: Stamp, op: &ListOp) {
+        self.io_ref.submit(list, op);
     }
     pub fn stamp(&self) -> Stamp {
         self.stamp
@@ -46,20 +57,31 @@ impl Io {
     }
     
     pub fn yarn(&self, yarn: String) -> Box<Future<Item=Yarn, Error=LoomError>> {
+        self.parse_yarn(yarn, None)
+    }
+    
+    /// Like `yarn`. Diagnostics refer to the source as `file`.
+    pub fn named_yarn(&self, file: &str, yarn: String) -> Box<Future<Item=Yarn, Error=LoomError>> {
+        self.parse_yarn(yarn, Some(file.into()))
+    }
+    
+    fn parse_yarn(&self, yarn: String, file: Option<IString>) -> Box<Future<Item=Yarn, Error=LoomError>> {
         let env = prepare_graph(self);
             
         let io = self.clone();
         // the lifetime of io.clone() ensures no borrow exists when the function
         // returns from this call
-        box Module::parse(io.clone(), env.clone(), yarn)
+        box Module::parse(io.clone(), env.clone(), yarn, file)
         .and_then(move |root: NodeP| {
             let io = io;
             // thus this call can not fail
             io.borrow_mut().insert_node(root.clone());
-            Ok(Yarn {
+            let yarn = Yarn {
                 root:   root,
                 env:    env.take()
-            })
+            };
+            yarn.resolve();
+            Ok(yarn)
         })
     }
     
@@ -78,6 +100,180 @@ impl Io {
         })
     }
     
+    /// Restore a document written by `Yarn::encode`, or replay a journal.
+    /// The hyphenators it uses are loaded from the data directory first.
+    /// The nodes keep their stamps.
+    pub fn decode(&self, data: Vec<u8>) -> Box<Future<Item=Yarn, Error=LoomError>> {
+        let names = {
+            let mut body = &data[..];
+            encoding::read_header(&mut body)
+            .and_then(|_| encoding::hyphenators(body))
+        };
+        let names = match names {
+            Ok(names) => names,
+            Err(e) => return box err(LoomError::Decode(e))
+        };
+        
+        let io = self.clone();
+        let hyphenators = names.into_iter()
+        .map(|files| self.hyphenator(&files).map(move |h| (files, h)))
+        .collect::<Vec<_>>();
+        
+        box join_all(hyphenators)
+        .and_then(move |hyphenators: Vec<(HyphenatorFiles, Shared<Hyphenator>)>| {
+            let mut body = &data[..];
+            encoding::read_header(&mut body).map_err(LoomError::Decode)?;
+            
+            let decoded = Decoder::new(body, hyphenators.into_iter().collect())
+                .run().map_err(LoomError::Decode)?;
+            
+            let root = match decoded.root {
+                Some(root) => root,
+                None => return Err(LoomError::Decode(DecodeError::Invalid("no root")))
+            };
+            if decoded.pending.len() > 0 {
+                warn!(io.log, "{} operations could not be applied", decoded.pending.len());
+            }
+            
+            {
+                let mut machine = io.borrow_mut();
+                machine.adopt(decoded.nodes);
+                machine.insert_node(root.clone());
+            }
+            Ok(Yarn {
+                root:   root,
+                env:    prepare_graph(&io).take()
+            })
+        })
+    }
+    
+    /// Build a document from its JSON representation (see `Yarn::to_json`).
+    pub fn from_json(&self, data: &str) -> Box<Future<Item=Yarn, Error=LoomError>> {
+        let root: JsonNode = match serde_json::from_str(data) {
+            Ok(root) => root,
+            Err(e) => return box err(LoomError::Json(e))
+        };
+        
+        let io = self.clone();
+        let hyphenators = json::hyphenators(&root).into_iter()
+        .map(|files| self.hyphenator(&files).map(move |h| (files, h)))
+        .collect::<Vec<_>>();
+        
+        box join_all(hyphenators)
+        .and_then(move |hyphenators: Vec<(HyphenatorFiles, Shared<Hyphenator>)>| {
+            let root = JsonReader::new(&io, hyphenators.into_iter().collect()).node(root)?;
+            io.borrow_mut().insert_node(root.clone());
+            
+            let yarn = Yarn {
+                root:   root,
+                env:    prepare_graph(&io).take()
+            };
+            yarn.resolve();
+            Ok(yarn)
+        })
+    }
+    
+    /// load a hyphenation dictionary and pattern file from the data directory
+    /// Imported documents can use it from now on.
+    ///
+    /// The patterns are used for words missing in the dictionary.
+    pub fn hyphenator(&self, files: &HyphenatorFiles) -> Box<Future<Item=Shared<Hyphenator>, Error=LoomError>> {
+        let io = self.clone();
+        let files = files.clone();
+        
+        let dictionary: Box<Future<Item=Hyphenator, Error=LoomError>> =
+            match files.dictionary {
+                Some(ref file) => self.dictionary(file),
+                None => box ok(Hyphenator::empty())
+            };
+        let patterns: Box<Future<Item=Option<Patterns>, Error=LoomError>> =
+            match files.patterns {
+                Some(ref file) => box self.config(|conf| open_read(&conf.data_dir, file))
+                    .map(|data| Some(Patterns::parse(&String::from_utf8_lossy(&data)))),
+                None => box ok(None)
+            };
+        
+        box dictionary.join(patterns).and_then(move |(mut h, patterns)| {
+            if let Some(p) = patterns {
+                h.set_patterns(p);
+            }
+            
+            let h = Shared::new(h);
+            io.borrow_mut().hyphenators.insert(files, h.clone());
+            Ok(h)
+        })
+    }
+    
+    // the pages of the file are shared with other processes
+    #[cfg(feature = "mmap")]
+    fn dictionary(&self, file: &str) -> Box<Future<Item=Hyphenator, Error=LoomError>> {
+        let path = self.config(|conf| conf.data_path.join(file));
+        box ::futures::future::result(Hyphenator::load_file(&path).map_err(|e| LoomError::Hyphenator(e)))
+    }
+    
+    #[cfg(not(feature = "mmap"))]
+    fn dictionary(&self, file: &str) -> Box<Future<Item=Hyphenator, Error=LoomError>> {
+        box self.config(|conf| open_read(&conf.data_dir, file))
+        .and_then(|data| Hyphenator::load(data.to_vec()).map_err(|e| LoomError::Hyphenator(e)))
+    }
+    
+    /// The operations of this machine since the last call, for `import` on another one.
+    /// The first export also contains the documents.
+    pub fn export(&self) -> Vec<u8> {
+        self.borrow_mut().export()
+    }
+    
+    /// Apply what another machine exported.
+    /// Operations that depend on something that did not arrive yet are kept
+    /// until it does. Returns the root of the last document in `data`.
+    pub fn import(&self, data: &[u8]) -> Result<Option<NodeP>, LoomError> {
+        let mut body = data;
+        encoding::read_header(&mut body).map_err(LoomError::Decode)?;
+        
+        let decoded = {
+            let machine = self.io.borrow();
+            let mut d = Decoder::new(body, machine.hyphenators.clone());
+            d.known(machine.nodes.iter());
+            d.run().map_err(LoomError::Decode)?
+        };
+        
+        let changed = {
+            let mut machine = self.borrow_mut();
+            machine.start_outbox();
+            if let Some(ref mut outbox) = machine.outbox {
+                // no need to send them back
+                for &stamp in decoded.nodes.keys() {
+                    outbox.state.known(stamp);
+                }
+            }
+            machine.adopt(decoded.nodes);
+            
+            let mut applied = decoded.applied;
+            machine.pending.extend(decoded.pending);
+            applied.extend(machine.retry());
+            
+            let mut changed = vec![];
+            for (list, op) in applied {
+                machine.record(list, &op);
+                if let Some(owner) = machine.owner(list) {
+                    changed.push(owner);
+                }
+            }
+            
+            if let Some(ref root) = decoded.root {
+                if !machine.roots.iter().any(|r| r.addr() == root.addr()) {
+                    machine.insert_node(root.clone());
+                }
+            }
+            changed
+        };
+        for owner in changed {
+            self.modified(&owner);
+        }
+        
+        Ok(decoded.root)
+    }
+    
     pub fn create(&self) -> IoCreate {
         IoCreate {
             stamp:  self.borrow_mut().stamp(),
@@ -85,11 +281,85 @@ impl Io {
         }
     }
     
+    /// Record an operation that was applied to the list with the given stamp
+    /// and tell everything that depends on the list.
+    pub fn submit(&self, list: Stamp, op: &ListOp) {
+        self.submit_all(list, slice::from_ref(op))
+    }
+    
+    /// Record several operations on one list as one edit. `modified` is called once.
+    pub fn submit_all(&self, list: Stamp, ops: &[ListOp]) {
+        // This is not Send -> submit can't be called twice at the same time
+        let owner = {
+            let mut machine = self.borrow_mut();
+            for op in ops {
+                machine.add_op(list, op);
+            }
+            machine.owner(list)
+        };
+        if let Some(owner) = owner {
+            self.modified(&owner);
+        }
+    }
+    
+    /// Call `modified` on the node, its parents and the nodes referencing any of them.
+    /// Each node is called once, the changed node first.
+    pub fn modified(&self, node: &NodeP) {
+        // collect first, the nodes may call back into io
+        let affected = self.io.borrow().affected(node);
+        for n in affected {
+            n.modified();
+        }
+    }
+    
     pub fn config<F, O>(&self, f: F) -> O where F: FnOnce(&Config) -> O {
         f(&self.io.borrow().config)
     }
+    
+    /// Record everything from now on. See `IoMachine::set_journal`.
+    pub fn set_journal(&self, out: Box<Write>) -> io::Result<()> {
+        self.borrow_mut().set_journal(out)
+    }
+    
+    pub fn flush(&self) -> io::Result<()> {
+        self.borrow_mut().flush()
+    }
+    
+    /// look up a node by its stamp
+    pub fn node(&self, stamp: Stamp) -> Option<NodeP> {
+        self.io.borrow().nodes.get(&stamp).cloned()
+    }
+    
+    /// the stamp of a registered node
+    pub fn stamp_of(&self, node: &NodeP) -> Option<Stamp> {
+        let machine = self.io.borrow();
+        match machine.ids.get(&node.addr()) {
+            Some(stamp) if machine.nodes.contains_key(stamp) => Some(*stamp),
+            _ => None
+        }
+    }
+    
+    /// See `IoMachine::delete_node`.
+    pub fn delete_node(&self, stamp: Stamp) -> Option<NodeP> {
+        self.borrow_mut().delete_node(stamp)
+    }
+    
+    /// See `IoMachine::collect`.
+    pub fn collect(&self) -> usize {
+        self.borrow_mut().collect()
+    }
+    
+    /// Forget the document and everything only it used.
+    pub fn close(&self, yarn: Yarn) -> usize {
+        let mut machine = self.borrow_mut();
+        machine.roots.retain(|r| r.addr() != yarn.root.addr());
+        machine.collect()
+    }
 }
 
+// give up on marks that keep moving
+const MAX_LAYOUT_PASSES: usize = 4;
+
 pub struct Yarn {
     root:   NodeP,
     env:    LocalEnv
@@ -98,6 +368,101 @@ impl Yarn {
     pub fn layout<W: Writer>(&self, w: &mut W) {
         self.root.layout(LayoutChain::root(&self.env), w)
     }
+    
+    /// Lay out the top-level blocks on `threads` threads.
+    /// Each one ends up in its own stream, in document order.
+    /// With the `sync` feature, `PngOutput` can be shared between the threads.
+    ///
+    /// The marks are found by `marks` beforehand, which lays out the whole document in one.
+    #[cfg(feature="sync")]
+    pub fn layout_parallel<O>(&self, output: &O, marks: &Marks, threads: usize) -> Vec<::layout::StreamVec<O>>
+    where O: Output + Sync, O::Word: Send, O::Font: Send + Sync
+    {
+        use crossbeam;
+        use std::sync::Mutex;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        
+        // the list stays locked until the layout is done
+        let items = self.root.list().map(|list| list.items());
+        let blocks: Vec<&NodeP> = match items {
+            Some(ref items) => items.iter().collect(),
+            None => vec![&self.root]
+        };
+        let root = LayoutChain::root(&self.env).with_marks(marks);
+        let env = root.link(&self.root);
+        
+        let next = AtomicUsize::new(0);
+        let streams: Vec<Mutex<::layout::StreamVec<O>>> = blocks.iter().map(|_| Mutex::new(vec![])).collect();
+        
+        crossbeam::scope(|s| {
+            for _ in 0 .. threads.max(1) {
+                s.spawn(|_| loop {
+                    let n = next.fetch_add(1, Ordering::SeqCst);
+                    let block = match blocks.get(n) {
+                        Some(block) => block,
+                        None => break
+                    };
+                    let mut w = GenericWriter::new(output);
+                    block.layout(env.clone(), &mut w);
+                    *streams[n].lock().unwrap() = w.into_stream();
+                });
+            }
+        }).expect("a layout thread panicked");
+        
+        streams.into_iter().map(|s| s.into_inner().unwrap()).collect()
+    }
+    
+    /// Resolve names that were not known when the blocks using them were built:
+    /// definitions that come later, or modules that were loaded since.
+    /// Macros that invoke themselves are reported.
+    /// Returns the nodes that are still unresolved.
+    pub fn resolve(&self) -> Vec<NodeP> {
+        let unresolved = environment::resolve(&self.root, &self.env);
+        
+        for cycle in document::cycles(&self.root) {
+            let names: Vec<&str> = cycle.iter().map(|n| n.name().unwrap_or("?")).collect();
+            error!(Log::root(), "recursive macro: {}", names.join(" → "));
+        }
+        unresolved
+    }
+    
+    pub fn to_json(&self) -> String {
+        let json = self.root.to_json(&mut JsonWriter::new());
+        serde_json::to_string_pretty(&json).expect("can't serialize the document")
+    }
+    
+    /// MessagePack representation of the document. See `Io::decode`.
+    pub fn encode(&self, io: &Io) -> Vec<u8> {
+        io.borrow_mut().encode(&self.root)
+    }
+    
+    /// layout again, using the marks collected in the previous pass
+    pub fn layout_with<W: Writer>(&self, marks: &Marks, w: &mut W) {
+        self.root.layout(LayoutChain::root(&self.env).with_marks(marks), w)
+    }
+    
+    /// Find out where the marks end up in columns of the given size.
+    ///
+    /// Filling in page numbers (toc, index) can move content to other pages.
+    /// So the layout is repeated until the marks do not change anymore.
+    pub fn marks<O>(&self, output: &O, width: Length, height: Length) -> Marks
+    where O: Output + fmt::Debug
+    {
+        let mut marks = Marks::new();
+        for _ in 0 .. MAX_LAYOUT_PASSES {
+            let mut w = GenericWriter::new(output);
+            self.layout_with(&marks, &mut w);
+            
+            let style = output.style_or_default("default");
+            let layout = ColumnLayout::with_params(w.finish(), width, height, style.breaks, style.columns);
+            let found = Marks::collect(layout.columns());
+            if found == marks {
+                break;
+            }
+            marks = found;
+        }
+        marks
+    }
 }
 impl fmt::Debug for Yarn {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -106,38 +471,176 @@ impl fmt::Debug for Yarn {
 }
 
 
-struct NodeType {
-    _name:   String,
-    //decode: Box<Fn(&mut Decoder<Vec<u8>>) -> Result<Decoder::Error>>,
+/// Append-only record of node creation, deletion and list operations,
+/// in the stream format of `encoding`. Replay it with `Io::decode`.
+pub struct Journal {
+    out:    Box<Write>,
+    state:  StreamState
 }
 
-enum StreamItem {
-    /// add a new node to the known nodes.
-    /// This if the name is known, the appropriate entry is inserted into the
-    /// IoMachine and the environment.
-    /// Optionally it can be tried to load the name from a dynamic library.
-    NodeDecl(String),
-    CreateNode(TypeId, DataSize, Stamp),
-    DeleteNode(Stamp),
-    Op(Stamp, DataSize)
+/// Local operations that were not exported yet
+struct Outbox {
+    data:   Vec<u8>,
+    state:  StreamState
 }
+
 pub struct IoMachine {
-    // we write everything to disk as well
-    // uncompressed data to go into compressor
     nodes:      HashMap<Stamp, NodeP>,
+    
+    // child -> parents, to propagate changes
+    parents:    HashMap<Stamp, Vec<Stamp>>,
+    
+    // list stamp -> node holding the list
+    lists:      HashMap<Stamp, Stamp>,
+    
+    // node address -> stamp, so shared nodes are stored once.
+    // Only registered nodes are in here, they can't be dropped while they are.
+    ids:        HashMap<usize, Stamp>,
+    
+    // documents in the order they were added
+    roots:      Vec<NodeP>,
+    
     stamper:    IncrementalStamper<u32, u32>,
-    typelist:   Vec<NodeType>,
     config:     Config,
+    
+    // we write everything to disk as well
+    journal:    Option<Journal>,
+    
+    // started by the first export
+    outbox:     Option<Outbox>,
+    
+    // remote operations waiting for their dependencies
+    pending:    Vec<(Stamp, ListOp)>,
+    
+    // loaded so far, by name
+    hyphenators: HashMap<HyphenatorFiles, Shared<Hyphenator>>
 }
 impl IoMachine {
-    fn add_data(&self, _stamp: Stamp, _data: &[u8]) {
+    // a local operation
+    fn add_op(&mut self, list: Stamp, op: &ListOp) {
+        self.record(list, op);
+        if let Some(ref mut outbox) = self.outbox {
+            let mut e = Encoder::new(&mut outbox.state, &self.ids, &mut self.stamper);
+            e.write_op(list, op);
+            outbox.data.extend(e.finish());
+        }
+    }
+    
+    fn record(&mut self, list: Stamp, op: &ListOp) {
+        // inserted nodes are new childs of the list owner.
+        // Removed ones stay in the index, they only cause a spurious `modified`.
+        if let Op::Ins { ref item, .. } = *op {
+            let child = self.register(item.clone());
+            if let Some(&owner) = self.lists.get(&list) {
+                self.add_parent(child, owner);
+            }
+        }
+        self.journal(|e| e.write_op(list, op));
+    }
+    fn add_parent(&mut self, child: Stamp, parent: Stamp) {
+        let parents = self.parents.entry(child).or_insert(vec![]);
+        if !parents.contains(&parent) {
+            parents.push(parent);
+        }
     }
     fn stamp(&mut self) -> Stamp {
         self.stamper.stamp()
     }
     
-    pub fn flush(&mut self) {
-        //self.encoder.flush();
+    // the node holding the list
+    fn owner(&self, list: Stamp) -> Option<NodeP> {
+        self.lists.get(&list).and_then(|s| self.nodes.get(s)).cloned()
+    }
+    
+    // apply pending operations until nothing changes, returns the applied ones
+    fn retry(&mut self) -> Vec<(Stamp, ListOp)> {
+        let mut applied = vec![];
+        loop {
+            let mut progress = false;
+            for (list, op) in mem::replace(&mut self.pending, vec![]) {
+                let unapplied = match self.owner(list) {
+                    Some(owner) => owner.list().unwrap().apply(op.clone()).err(),
+                    None => Some(op.clone())
+                };
+                match unapplied {
+                    Some(op) => self.pending.push((list, op)),
+                    None => {
+                        applied.push((list, op));
+                        progress = true;
+                    }
+                }
+            }
+            if !progress {
+                return applied;
+            }
+        }
+    }
+    
+    // Local operations are collected from the first export or import on.
+    // The documents known at that time are sent first.
+    fn start_outbox(&mut self) {
+        if self.outbox.is_some() {
+            return;
+        }
+        let mut outbox = Outbox {
+            data:   vec![],
+            state:  StreamState::new()
+        };
+        for root in self.roots.iter() {
+            let mut e = Encoder::new(&mut outbox.state, &self.ids, &mut self.stamper);
+            let stamp = e.write(root);
+            e.write_item(StreamItem::Root(stamp));
+            outbox.data.extend(e.finish());
+        }
+        self.outbox = Some(outbox);
+    }
+    
+    fn export(&mut self) -> Vec<u8> {
+        self.start_outbox();
+        let outbox = self.outbox.as_mut().unwrap();
+        let mut out = encoding::header();
+        out.extend(outbox.data.drain(..));
+        outbox.state.restart();
+        out
+    }
+    
+    // write to the journal, if there is one
+    fn journal<F>(&mut self, f: F) where F: FnOnce(&mut Encoder) {
+        if let Some(ref mut journal) = self.journal {
+            let data = {
+                let mut e = Encoder::new(&mut journal.state, &self.ids, &mut self.stamper);
+                f(&mut e);
+                e.finish()
+            };
+            if let Err(e) = journal.out.write_all(&data) {
+                error!(Log::root(), "can't write to the journal: {:?}", e);
+            }
+        }
+    }
+    
+    pub fn flush(&mut self) -> io::Result<()> {
+        match self.journal {
+            Some(ref mut journal) => journal.out.flush(),
+            None => Ok(())
+        }
+    }
+    
+    /// Start a new journal.
+    /// It begins with the documents known so far, so it can be replayed on its own.
+    pub fn set_journal(&mut self, mut out: Box<Write>) -> io::Result<()> {
+        out.write_all(&encoding::header())?;
+        self.journal = Some(Journal {
+            out:    out,
+            state:  StreamState::new()
+        });
+        
+        for root in self.roots.clone() {
+            self.journal(|e| {
+                let stamp = e.write(&root);
+                e.write_item(StreamItem::Root(stamp));
+            });
+        }
+        Ok(())
     }
     
     /// storage: Some(path) to store the document
@@ -146,30 +649,182 @@ impl IoMachine {
         
         IoMachine {
             nodes:      HashMap::new(),
+            parents:    HashMap::new(),
+            lists:      HashMap::new(),
+            ids:        HashMap::new(),
+            roots:      vec![],
             stamper:    IncrementalStamper::init_random(),
-            typelist:   vec![],
-            config:     config
+            config:     config,
+            journal:    None,
+            outbox:     None,
+            pending:    vec![],
+            hyphenators: HashMap::new()
         }
     }
     
-    pub fn insert_node(&mut self, node: NodeP) {
+    /// Register the document with the given root.
+    /// Nodes that are known already keep their stamp.
+    pub fn insert_node(&mut self, node: NodeP) -> Stamp {
+        let stamp = self.register(node.clone());
+        
+        self.roots.push(node.clone());
+        self.journal(|e| {
+            let stamp = e.write(&node);
+            e.write_item(StreamItem::Root(stamp));
+        });
+        if let Some(ref mut outbox) = self.outbox {
+            let mut e = Encoder::new(&mut outbox.state, &self.ids, &mut self.stamper);
+            let stamp = e.write(&node);
+            e.write_item(StreamItem::Root(stamp));
+            outbox.data.extend(e.finish());
+        }
+        
+        stamp
+    }
+    
+    // stamp the node and everything below it, and index the parents
+    fn register(&mut self, node: NodeP) -> Stamp {
         // Nodes may have strange links. Avoid recursion!
-        let mut queue = vec![node];
+        // Childs are stamped before their parents, as in the journal.
+        let mut queue = vec![(node.clone(), false)];
         
-        while let Some(n) = queue.pop() {
-            // add childs to quue
-            n.childs(&mut queue);
-            
-            // make up an ID
-            let id = self.stamper.stamp();
+        while let Some((n, childs_done)) = queue.pop() {
+            let known = match self.ids.get(&n.addr()) {
+                Some(id) => self.nodes.contains_key(id),
+                None => false
+            };
+            if known {
+                continue;
+            }
             
+            let mut childs = vec![];
+            n.childs(&mut childs);
             
-            // push into queue
-            //self.queue_out.push(Shared{ id: id, node: n });
-            
-            // store object (consumes it)
-            self.nodes.insert(id, n);
+            if childs_done {
+                let id = self.id(&n);
+                for c in childs {
+                    let child = self.id(&c);
+                    self.add_parent(child, id);
+                }
+                if let Some(list) = n.list() {
+                    self.lists.insert(list.stamp(), id);
+                }
+                
+                // store object (consumes it)
+                self.nodes.insert(id, n);
+            } else {
+                // add childs to queue
+                queue.push((n, true));
+                queue.extend(childs.into_iter().map(|c| (c, false)));
+            }
         }
+        
+        self.id(&node)
+    }
+    
+    fn id(&mut self, node: &NodeP) -> Stamp {
+        let stamper = &mut self.stamper;
+        *self.ids.entry(node.addr()).or_insert_with(|| stamper.stamp())
+    }
+    
+    // the node, its ancestors and what references them, each once
+    fn affected(&self, node: &NodeP) -> Vec<NodeP> {
+        let mut seen = HashSet::new();
+        let mut out = vec![];
+        let mut queue = VecDeque::new();
+        queue.push_back(node.clone());
+        
+        while let Some(n) = queue.pop_front() {
+            if !seen.insert(n.addr()) {
+                continue;
+            }
+            if let Some(parents) = self.ids.get(&n.addr()).and_then(|id| self.parents.get(id)) {
+                queue.extend(parents.iter().filter_map(|p| self.nodes.get(p)).cloned());
+            }
+            queue.extend(n.references());
+            out.push(n);
+        }
+        out
+    }
+    
+    /// Forget the node and record the deletion in the journal.
+    /// Its childs are not touched, see `collect`.
+    pub fn delete_node(&mut self, stamp: Stamp) -> Option<NodeP> {
+        let node = match self.nodes.remove(&stamp) {
+            Some(node) => node,
+            None => return None
+        };
+        self.ids.remove(&node.addr());
+        self.parents.remove(&stamp);
+        if let Some(list) = node.list() {
+            if self.lists.get(&list.stamp()) == Some(&stamp) {
+                self.lists.remove(&list.stamp());
+            }
+        }
+        self.roots.retain(|r| r.addr() != node.addr());
+        self.journal(|e| e.write_item(StreamItem::DeleteNode(stamp)));
+        
+        Some(node)
+    }
+    
+    /// Delete the nodes that can not be reached from the documents,
+    /// or from remote operations waiting to be applied.
+    /// Returns how many there were.
+    pub fn collect(&mut self) -> usize {
+        let mut reachable = HashSet::new();
+        let mut queue = self.roots.clone();
+        for &(_, ref op) in self.pending.iter() {
+            if let Op::Ins { ref item, .. } = *op {
+                queue.push(item.clone());
+            }
+        }
+        
+        while let Some(n) = queue.pop() {
+            if !reachable.insert(n.addr()) {
+                continue;
+            }
+            n.childs(&mut queue);
+            if let Some(target) = n.target() {
+                queue.push(target);
+            }
+        }
+        
+        let dead: Vec<Stamp> = self.nodes.iter()
+            .filter(|&(_, n)| !reachable.contains(&n.addr()))
+            .map(|(&stamp, _)| stamp)
+            .collect();
+        for &stamp in dead.iter() {
+            self.delete_node(stamp);
+        }
+        
+        let nodes = &self.nodes;
+        for parents in self.parents.values_mut() {
+            parents.retain(|p| nodes.contains_key(p));
+        }
+        
+        dead.len()
+    }
+    
+    // decoded nodes keep their stamps
+    fn adopt(&mut self, nodes: HashMap<Stamp, NodeP>) {
+        for (stamp, node) in nodes.iter() {
+            self.ids.insert(node.addr(), *stamp);
+        }
+        for (_, node) in nodes {
+            self.register(node);
+        }
+    }
+    
+    /// encode the document with the given root on its own
+    pub fn encode(&mut self, root: &NodeP) -> Vec<u8> {
+        let mut state = StreamState::new();
+        let mut out = encoding::header();
+        
+        let mut e = Encoder::new(&mut state, &self.ids, &mut self.stamper);
+        let stamp = e.write(root);
+        e.write_item(StreamItem::Root(stamp));
+        out.extend(e.finish());
+        out
     }
     
     pub fn to_ref(self) -> Io {
@@ -198,3 +853,269 @@ pub fn open_read(dir: &Directory, name: &str) -> Box<Future<Item=Data, Error=Loo
     .map_err(|e| LoomError::DirectoryGetFile(e))
     .and_then(|file| file.read().map_err(|e| LoomError::FileRead(e)))
 }
+
+#[cfg(test)]
+pub fn machine() -> Io {
+    let dir = Directory::open(".").wait().unwrap();
+    IoMachine::new(Config {
+        style_dir:  dir.clone(),
+        data_dir:   dir.clone(),
+        font_dir:   dir.clone(),
+        yarn_dir:   dir,
+        data_path:  ".".into()
+    }).to_ref()
+}
+
+#[cfg(test)]
+fn text(node: &Node) -> String {
+    use layout::TextWriter;
+    
+    let env = LocalEnv::new();
+    let mut w = TextWriter::new();
+    node.layout(LayoutChain::root(&env), &mut w);
+    w.into_string()
+}
+
+#[test]
+fn test_converge() {
+    use document::{Ptr, NodeList, NodeListP};
+    use nodes::Word;
+    
+    let word = |s| -> NodeP { Ptr::new(Word::new(s)).into() };
+    let a = machine();
+    let b = machine();
+    
+    let list: NodeListP = Ptr::new(NodeList::from(&a,
+        vec![word("one"), word("two"), word("three")].into_iter()
+    ));
+    a.borrow_mut().insert_node(list.clone().into());
+    
+    let root = b.import(&a.export()).unwrap().unwrap();
+    let remote = root.list().unwrap();
+    assert_eq!(text(&*list), text(remote));
+    
+    list.insert(&a, 1, word("a")).unwrap();
+    remote.insert(&b, 1, word("b")).unwrap();
+    remote.remove(&b, 0).unwrap();
+    let to_b = a.export();
+    list.replace(&a, 3, word("c")).unwrap();
+    
+    a.import(&b.export()).unwrap();
+    b.import(&to_b).unwrap();
+    b.import(&a.export()).unwrap();
+    
+    assert_eq!(list.size(), 4);
+    assert_eq!(text(&*list), text(remote));
+}
+
+// does not run anything; fails to compile if the bounds can't be met
+#[cfg(all(feature="sync", feature="output_png"))]
+#[test]
+fn test_parallel_png() {
+    use output::png::PngOutput;
+    
+    fn sync<T: Sync>() {}
+    sync::<PngOutput>();
+    sync::<LocalEnv>();
+    let _ = Yarn::layout_parallel::<PngOutput>;
+}
+
+#[cfg(feature="sync")]
+#[test]
+fn test_parallel_layout() {
+    use layout::{Entry, TestOutput};
+    
+    let source = "/greet\n    Hello $args!\n\n:greet world\n    A (small) test.\n\nSome words here.\n\nAnd more.\n";
+    let a = machine();
+    let yarn = a.yarn(source.into()).wait().unwrap();
+    let output = TestOutput::new();
+    let marks = yarn.marks(&output, 20.0, 20.0);
+    
+    // glue between the blocks differs, the words do not
+    let words = |stream: &[Entry<TestOutput>]| -> Vec<f32> {
+        stream.iter().filter_map(|e| match *e {
+            Entry::Word(w) => Some(w),
+            _ => None
+        }).collect()
+    };
+    let mut w = GenericWriter::new(&output);
+    yarn.layout_with(&marks, &mut w);
+    let sequential = words(&w.finish()[..]);
+    
+    let parallel: Vec<f32> = yarn.layout_parallel(&output, &marks, 3).iter()
+        .flat_map(|s| words(&s[..]))
+        .collect();
+    assert!(sequential.len() > 0);
+    assert_eq!(sequential, parallel);
+}
+
+#[test]
+fn test_encode_roundtrip() {
+    let source = "/greet\n    Hello $args!\n\n:greet world\n    A (small) test, with + symbols.\n";
+    let a = machine();
+    let yarn = a.yarn(source.into()).wait().unwrap();
+    let data = yarn.encode(&a);
+    
+    let b = machine();
+    let decoded = b.decode(data).wait().unwrap();
+    assert_eq!(decoded.to_json(), yarn.to_json());
+}
+
+// a journal that can be read back
+#[cfg(test)]
+struct Buffer(Rc<RefCell<Vec<u8>>>);
+#[cfg(test)]
+impl Write for Buffer {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(data);
+        Ok(data.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_journal_replay() {
+    use document::Ptr;
+    use nodes::Word;
+    
+    let a = machine();
+    let journal = Rc::new(RefCell::new(vec![]));
+    a.set_journal(box Buffer(journal.clone())).unwrap();
+    
+    let yarn = a.yarn("Some words here.\n\nAnd more.\n".into()).wait().unwrap();
+    {
+        let body = yarn.root.list().unwrap();
+        let leaf = body.get(0).unwrap();
+        let words = leaf.list().unwrap();
+        words.insert(&a, 1, Ptr::new(Word::new("new")).into()).unwrap();
+        words.remove(&a, 0).unwrap();
+        body.remove(&a, 1).unwrap();
+    }
+    a.flush().unwrap();
+    
+    let data = journal.borrow().clone();
+    let replayed = machine().decode(data).wait().unwrap();
+    assert_eq!(replayed.to_json(), yarn.to_json());
+}
+
+// counts how often it was told about a change
+#[cfg(test)]
+struct Probe {
+    list:   document::NodeListP,
+    count:  ::shared::Lock<usize>
+}
+#[cfg(test)]
+impl Node for Probe {
+    fn type_name(&self) -> &'static str {
+        "probe"
+    }
+    fn encode(&self, e: &mut Encoder) {
+        e.node(&self.list);
+    }
+    fn to_json(&self, j: &mut JsonWriter) -> JsonNode {
+        self.list.to_json(j)
+    }
+    fn childs(&self, out: &mut Vec<NodeP>) {
+        out.push(self.list.clone().into());
+    }
+    fn modified(&self) {
+        *self.count.borrow_mut() += 1;
+    }
+    fn layout(&self, env: LayoutChain, w: &mut Writer) {
+        self.list.layout(env, w)
+    }
+}
+
+#[test]
+fn test_propagation() {
+    use document::{Ptr, NodeList, NodeListP};
+    use nodes::{Word, Definition};
+    use shared::Lock;
+    use source::Span;
+    
+    let word = |s| -> NodeP { Ptr::new(Word::new(s)).into() };
+    let a = machine();
+    let probe = |items: Vec<NodeP>| Ptr::new(Probe {
+        list:   Ptr::new(NodeList::from(&a, items.into_iter())),
+        count:  Lock::new(0)
+    });
+    
+    let args: NodeListP = Ptr::new(NodeList::from(&a, vec![word("name")].into_iter()));
+    let body: NodeListP = Ptr::new(NodeList::from(&a, vec![].into_iter()));
+    let def: NodeP = Ptr::new(Definition::new("greet".into(), args.clone(), body, LocalEnv::new(), Span::default())).into();
+    
+    // `parent` contains the definition, `user` invokes it
+    let parent = probe(vec![def.clone()]);
+    let user = probe(vec![]);
+    def.add_ref(&user.clone().into());
+    a.borrow_mut().insert_node(parent.clone().into());
+    assert_eq!(def.signature().unwrap().arity, Some(1));
+    
+    args.insert(&a, 1, word("title")).unwrap();
+    assert_eq!(def.signature().unwrap().arity, Some(2));
+    assert_eq!(*parent.count.borrow(), 1);
+    assert_eq!(*user.count.borrow(), 1);
+    
+    args.remove(&a, 0).unwrap();
+    assert_eq!(def.signature().unwrap().arity, Some(1));
+    
+    // one edit, even if it is a remove and an insert
+    args.replace(&a, 0, word("name")).unwrap();
+    assert_eq!(*parent.count.borrow(), 3);
+    assert_eq!(*user.count.borrow(), 3);
+}
+
+#[test]
+fn test_collect() {
+    use document::Ptr;
+    use nodes::Word;
+    
+    let a = machine();
+    let yarn = a.yarn("Some words here.\n\nAnd more.\n".into()).wait().unwrap();
+    let removed = yarn.root.list().unwrap().remove(&a, 1).unwrap();
+    let stamp = a.stamp_of(&removed).unwrap();
+    
+    // still registered until it is collected
+    assert!(a.node(stamp).is_some());
+    assert!(a.collect() > 0);
+    assert!(a.node(stamp).is_none());
+    assert!(a.stamp_of(&removed).is_none());
+    assert_eq!(a.collect(), 0);
+    
+    let kept = yarn.root.list().unwrap().get(0).unwrap();
+    let stamp = a.stamp_of(&kept).unwrap();
+    assert!(a.delete_node(stamp).is_some());
+    assert!(a.delete_node(stamp).is_none());
+    
+    // nodes the encoder has not seen registered do not keep a stamp
+    let word: NodeP = Ptr::new(Word::new("fresh")).into();
+    a.borrow_mut().encode(&word);
+    assert!(a.stamp_of(&word).is_none());
+    
+    let decoded = machine().decode(yarn.encode(&a)).wait().unwrap();
+    assert_eq!(decoded.to_json(), yarn.to_json());
+}
+
+#[test]
+fn test_nested_macros() {
+    use layout::TextWriter;
+    
+    let a = machine();
+    let render = |source: &str| {
+        let yarn = a.yarn(source.into()).wait().unwrap();
+        let mut w = TextWriter::new();
+        yarn.layout(&mut w);
+        w.into_string()
+    };
+    
+    // the inner quote is part of what is passed to the outer one
+    let nested = render("/quote\n    begin $body end\n\n:quote\n    outer\n\n    :quote\n        inner\n");
+    assert!(nested.contains("inner"));
+    assert_eq!(nested.matches("begin").count(), 2);
+    assert!(!nested.contains("recursive"));
+    
+    let looping = render("/loop\n    again\n\n    :loop\n\n:loop\n");
+    assert!(looping.contains("recursive"));
+}
@@ -0,0 +1,397 @@
+//! JSON representation of the document tree, for other tools.
+//!
+//! Blocks and groups refer to their macro by name. The names are resolved
+//! again after reading (see `Yarn::resolve`).
+//! Nodes held by environments can be used in several places.
+//! They are written once and referred to by `id` afterwards.
+
+use std::collections::HashMap;
+use shared::Shared;
+use document::{Node, NodeP, NodeList, NodeListP, Ptr, Placeholder, Span, GroupRef};
+use environment::{LocalEnv, Fields};
+use hyphenation::{Hyphenator, HyphenatorFiles};
+use layout::Glue;
+use nodes::*;
+use io::Io;
+use istring::IString;
+use LoomError;
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum JsonNode {
+    Module {
+        env:        JsonEnv,
+        body:       Vec<JsonNode>
+    },
+    Block {
+        name:       String,
+        env:        JsonEnv,
+        args:       Option<Vec<JsonNode>>,
+        body:       Option<Vec<JsonNode>>,
+        #[serde(default)]
+        span:       JsonSpan
+    },
+    Definition {
+        name:       String,
+        env:        JsonEnv,
+        args:       Vec<JsonNode>,
+        body:       Vec<JsonNode>,
+        #[serde(default)]
+        span:       JsonSpan
+    },
+    Leaf {
+        items:      Vec<JsonNode>
+    },
+    List {
+        items:      Vec<JsonNode>
+    },
+    NodeList {
+        items:      Vec<JsonNode>
+    },
+    Group {
+        opening:    String,
+        closing:    String,
+        content:    Vec<JsonNode>,
+        delimiters: (String, String),
+        #[serde(default)]
+        inner:      JsonGlue
+    },
+    Aside {
+        inner:      JsonShared
+    },
+    Word {
+        text:       String
+    },
+    Punctuation {
+        text:       String,
+        #[serde(default)]
+        left:       JsonGlue
+    },
+    Symbol {
+        text:       String
+    },
+    Token {
+        text:       String
+    },
+    /// `body`, `args`, the argument number or the unknown name
+    Placeholder {
+        name:       String
+    },
+    Keyword,
+    Index,
+    Toc
+}
+
+/// A node that may be used in more than one place.
+/// `node` is only present the first time.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct JsonShared {
+    pub id:     u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub node:   Option<Box<JsonNode>>
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct JsonEnv {
+    #[serde(default)]
+    pub commands:   Vec<String>,
+    #[serde(default)]
+    pub targets:    Vec<JsonTarget>,
+    #[serde(default)]
+    pub groups:     Vec<JsonGroup>,
+    /// the dictionary of the hyphenator
+    #[serde(default)]
+    pub hyphenator: Option<String>,
+    /// its pattern file
+    #[serde(default)]
+    pub patterns:   Option<String>,
+    #[serde(default)]
+    pub symbols:    Vec<(String, String)>,
+    #[serde(default)]
+    pub language:   Option<String>,
+    #[serde(default)]
+    pub toc:        Option<u8>,
+    #[serde(default)]
+    pub hyphenmin:  Option<(usize, usize)>
+}
+impl JsonEnv {
+    /// None without a hyphenator
+    pub fn hyphenator_files(&self) -> Option<HyphenatorFiles> {
+        if self.hyphenator.is_none() && self.patterns.is_none() {
+            return None;
+        }
+        Some(HyphenatorFiles {
+            dictionary: self.hyphenator.as_ref().map(|s| s.as_str().into()),
+            patterns:   self.patterns.as_ref().map(|s| s.as_str().into())
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct JsonTarget {
+    pub name:   String,
+    pub node:   JsonShared
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct JsonGroup {
+    pub opening:    String,
+    pub closing:    String,
+    pub node:       JsonShared
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct JsonSpan {
+    pub offset: usize,
+    pub line:   usize,
+    
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file:   Option<String>
+}
+impl<'a> From<&'a Span> for JsonSpan {
+    fn from(s: &'a Span) -> JsonSpan {
+        JsonSpan {
+            offset: s.offset,
+            line:   s.line,
+            file:   s.file.as_ref().map(|f| f.to_string())
+        }
+    }
+}
+impl Into<Span> for JsonSpan {
+    fn into(self) -> Span {
+        Span {
+            offset: self.offset,
+            line:   self.line,
+            file:   self.file.map(IString::from)
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum JsonGlue {
+    None,
+    Space { breaking: bool, scale: f32 },
+    Newline { fill: bool }
+}
+impl Default for JsonGlue {
+    fn default() -> JsonGlue {
+        JsonGlue::None
+    }
+}
+impl From<Glue> for JsonGlue {
+    fn from(g: Glue) -> JsonGlue {
+        match g {
+            Glue::None => JsonGlue::None,
+            Glue::Space { breaking, scale } => JsonGlue::Space { breaking: breaking, scale: scale },
+            Glue::Newline { fill } => JsonGlue::Newline { fill: fill }
+        }
+    }
+}
+impl Into<Glue> for JsonGlue {
+    fn into(self) -> Glue {
+        match self {
+            JsonGlue::None => Glue::None,
+            JsonGlue::Space { breaking, scale } => Glue::Space { breaking: breaking, scale: scale },
+            JsonGlue::Newline { fill } => Glue::Newline { fill: fill }
+        }
+    }
+}
+
+/// Turns nodes into `JsonNode`s. See `Node::to_json`.
+pub struct JsonWriter {
+    // node address -> id of nodes written so far
+    shared: HashMap<usize, u32>
+}
+impl JsonWriter {
+    pub fn new() -> JsonWriter {
+        JsonWriter {
+            shared: HashMap::new()
+        }
+    }
+    pub fn node<N: Node + ?Sized>(&mut self, node: &Ptr<N>) -> JsonNode {
+        node.to_json(self)
+    }
+    pub fn list(&mut self, list: &NodeList<NodeP>) -> Vec<JsonNode> {
+        let items = list.items();
+        let out = items.iter().map(|n| n.to_json(self)).collect();
+        out
+    }
+    pub fn shared(&mut self, node: &NodeP) -> JsonShared {
+        if let Some(&id) = self.shared.get(&node.addr()) {
+            return JsonShared { id: id, node: None };
+        }
+        let id = self.shared.len() as u32;
+        self.shared.insert(node.addr(), id);
+        JsonShared {
+            id:     id,
+            node:   Some(box node.to_json(self))
+        }
+    }
+}
+
+/// Builds nodes from `JsonNode`s.
+pub struct JsonReader {
+    io:             Io,
+    shared:         HashMap<u32, NodeP>,
+    hyphenators:    HashMap<HyphenatorFiles, Shared<Hyphenator>>
+}
+impl JsonReader {
+    /// The hyphenators the document uses have to be loaded (see `hyphenators`).
+    pub fn new(io: &Io, hyphenators: HashMap<HyphenatorFiles, Shared<Hyphenator>>) -> JsonReader {
+        JsonReader {
+            io:             io.clone(),
+            shared:         HashMap::new(),
+            hyphenators:    hyphenators
+        }
+    }
+
+    pub fn io(&self) -> &Io {
+        &self.io
+    }
+
+    pub fn hyphenator(&self, files: &HyphenatorFiles) -> Result<Shared<Hyphenator>, LoomError> {
+        match self.hyphenators.get(files) {
+            Some(h) => Ok(h.clone()),
+            None => Err(LoomError::MissingItem(files.to_string().into()))
+        }
+    }
+
+    pub fn shared(&mut self, s: JsonShared) -> Result<NodeP, LoomError> {
+        match s.node {
+            Some(node) => {
+                let node = self.node(*node)?;
+                self.shared.insert(s.id, node.clone());
+                Ok(node)
+            },
+            None => match self.shared.get(&s.id) {
+                Some(node) => Ok(node.clone()),
+                None => Err(LoomError::MissingItem(format!("node {}", s.id).into()))
+            }
+        }
+    }
+
+    pub fn nodes(&mut self, items: Vec<JsonNode>) -> Result<Vec<NodeP>, LoomError> {
+        items.into_iter().map(|n| self.node(n)).collect()
+    }
+    pub fn list(&mut self, items: Vec<JsonNode>) -> Result<NodeListP, LoomError> {
+        let nodes = self.nodes(items)?;
+        Ok(Ptr::new(NodeList::from(&self.io, nodes.into_iter())))
+    }
+
+    pub fn node(&mut self, node: JsonNode) -> Result<NodeP, LoomError> {
+        let node: NodeP = match node {
+            JsonNode::Module { env, body } => {
+                let env = LocalEnv::from_json(self, env)?;
+                Ptr::new(Module::new(env, self.list(body)?)).into()
+            },
+            JsonNode::Block { name, env, args, body, span } => {
+                let env = LocalEnv::from_json(self, env)?;
+                let fields = Fields {
+                    args:   match args { Some(a) => Some(self.list(a)?), None => None },
+                    body:   match body { Some(b) => Some(self.list(b)?), None => None }
+                };
+                Ptr::new(Block::new(name, env, fields, span.into())).into()
+            },
+            JsonNode::Definition { name, env, args, body, span } => {
+                let env = LocalEnv::from_json(self, env)?;
+                let args = self.list(args)?;
+                let body = self.list(body)?;
+                Ptr::new(Definition::new(name, args, body, env, span.into())).into()
+            },
+            JsonNode::Leaf { items } => {
+                let content = self.nodes(items)?;
+                Ptr::new(Leaf::new(NodeList::from(&self.io, content.into_iter()))).into()
+            },
+            JsonNode::List { items } => {
+                let items = self.nodes(items)?;
+                Ptr::new(List::new(NodeList::from(&self.io, items.into_iter()))).into()
+            },
+            JsonNode::NodeList { items } => self.list(items)?.into(),
+            JsonNode::Group { opening, closing, content, delimiters, inner } => {
+                let content = self.list(content)?;
+                let delimiters = (delimiters.0.into(), delimiters.1.into());
+                Ptr::new(Group::new(
+                    GroupRef::new(opening.into(), closing.into()),
+                    content, delimiters, inner.into()
+                )).into()
+            },
+            JsonNode::Aside { inner } => Ptr::new(Aside::new(self.shared(inner)?)).into(),
+            JsonNode::Word { text } => Ptr::new(Word::new(&text)).into(),
+            JsonNode::Punctuation { text, left } => Ptr::new(Punctuation::with_glue(&text, left.into())).into(),
+            JsonNode::Symbol { text } => Ptr::new(Symbol::raw(&text)).into(),
+            JsonNode::Token { text } => Ptr::new(Token::new(text.into())).into(),
+            JsonNode::Placeholder { name } => {
+                let p = match &*name {
+                    "body" => Placeholder::Body,
+                    "args" => Placeholder::Arguments,
+                    s => match s.parse() {
+                        Ok(n) => Placeholder::Argument(n),
+                        Err(_) => Placeholder::Unknown(s.into())
+                    }
+                };
+                Ptr::new(p).into()
+            },
+            JsonNode::Keyword => Ptr::new(Keyword).into(),
+            JsonNode::Index => Ptr::new(Index).into(),
+            JsonNode::Toc => Ptr::new(Toc).into()
+        };
+        Ok(node)
+    }
+}
+
+/// the files of the hyphenators used by the document
+pub fn hyphenators(node: &JsonNode) -> Vec<HyphenatorFiles> {
+    fn env(e: &JsonEnv, out: &mut Vec<HyphenatorFiles>) {
+        if let Some(files) = e.hyphenator_files() {
+            if !out.contains(&files) {
+                out.push(files);
+            }
+        }
+        for t in e.targets.iter() {
+            shared(&t.node, out);
+        }
+        for g in e.groups.iter() {
+            shared(&g.node, out);
+        }
+    }
+    fn shared(s: &JsonShared, out: &mut Vec<HyphenatorFiles>) {
+        if let Some(ref n) = s.node {
+            walk(n, out);
+        }
+    }
+    fn list(items: &[JsonNode], out: &mut Vec<HyphenatorFiles>) {
+        for n in items {
+            walk(n, out);
+        }
+    }
+    fn walk(node: &JsonNode, out: &mut Vec<HyphenatorFiles>) {
+        match *node {
+            JsonNode::Module { env: ref e, ref body } => {
+                env(e, out);
+                list(body, out);
+            },
+            JsonNode::Block { env: ref e, ref args, ref body, .. } => {
+                env(e, out);
+                for l in args.iter().chain(body.iter()) {
+                    list(l, out);
+                }
+            },
+            JsonNode::Definition { env: ref e, ref args, ref body, .. } => {
+                env(e, out);
+                list(args, out);
+                list(body, out);
+            },
+            JsonNode::Leaf { ref items } |
+            JsonNode::List { ref items } |
+            JsonNode::NodeList { ref items } => list(items, out),
+            JsonNode::Group { ref content, .. } => list(content, out),
+            JsonNode::Aside { ref inner } => shared(inner, out),
+            _ => {}
+        }
+    }
+
+    let mut out = vec![];
+    walk(node, &mut out);
+    out
+}
@@ -0,0 +1,229 @@
+use istring::IString;
+use layout::Glue;
+use source::Item;
+
+/// What kind of item a rule applies to
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Class {
+    Word,
+    Punctuation,
+    Symbol
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Context {
+    Any,
+
+    /// only between two numbers, like `1 - 5`
+    Numeric
+}
+
+/// Replace `pattern` by `replace`.
+/// Punctuation and symbols have to match as a whole,
+/// within words every occurence is replaced.
+#[derive(Debug)]
+pub struct Rule {
+    class:      Class,
+    context:    Context,
+    pattern:    IString,
+    replace:    IString
+}
+impl Rule {
+    pub fn new(class: Class, context: Context, pattern: &str, replace: &str) -> Rule {
+        Rule {
+            class:      class,
+            context:    context,
+            pattern:    pattern.into(),
+            replace:    replace.into()
+        }
+    }
+}
+
+/// Typographic conventions of a language.
+/// Applied when the Word, Punctuation and Symbol nodes are built.
+#[derive(Debug)]
+pub struct Language {
+    name:       IString,
+
+    // opening and closing double quotes
+    quotes:     (IString, IString),
+
+    // between the quotes and the quoted text
+    quote_space: &'static str,
+
+    // punctuation starting with one of these is preceded by a thin space
+    spaced:     &'static str,
+
+    rules:      Vec<Rule>
+}
+
+// U+202F, a thin space that does not break
+const NARROW_NBSP: &str = "\u{202F}";
+
+fn common_rules() -> Vec<Rule> {
+    vec![
+        Rule::new(Class::Punctuation,   Context::Any,       "...",  "…"),
+        Rule::new(Class::Word,          Context::Any,       "...",  "…"),
+        Rule::new(Class::Symbol,        Context::Numeric,   "-",    "–"),
+        Rule::new(Class::Symbol,        Context::Numeric,   "--",   "–"),
+    ]
+}
+
+fn is_number(item: Option<&Item>) -> bool {
+    match item {
+        Some(&Item::Word(ref s)) => s.len() > 0 && s.chars().all(|c| c.is_numeric()),
+        _ => false
+    }
+}
+
+impl Language {
+    /// `de`, `en`, `fr`. Regional variants (`en-GB`) use the base language.
+    pub fn by_name(name: &str) -> Option<Language> {
+        let base = name.split(|c| c == '-' || c == '_').next().unwrap_or(name);
+        let (quotes, quote_space, spaced) = match base {
+            "en" => (("“", "”"), "", ""),
+            "de" => (("„", "“"), "", ""),
+            "fr" => (("«", "»"), NARROW_NBSP, ";:!?"),
+            _ => return None
+        };
+
+        Some(Language {
+            name:       name.into(),
+            quotes:     (quotes.0.into(), quotes.1.into()),
+            quote_space: quote_space,
+            spaced:     spaced,
+            rules:      common_rules()
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn add_rule(&mut self, rule: Rule) {
+        self.rules.push(rule);
+    }
+
+    pub fn quotes(&self) -> (&str, &str) {
+        (&self.quotes.0, &self.quotes.1)
+    }
+
+    /// Typographic quotes for a word starting or ending with `"`.
+    /// Trailing punctuation may follow the closing quote: `end",`.
+    /// After a digit, `"` is an inch mark and stays.
+    pub fn replace_quotes(&self, word: &str) -> Option<String> {
+        if !word.contains('"') || word == "\"" {
+            return None;
+        }
+        
+        let mut out = String::with_capacity(word.len() + 8);
+        let rest = match word.starts_with('"') {
+            true => {
+                out.push_str(&self.quotes.0);
+                out.push_str(self.quote_space);
+                &word[1 ..]
+            },
+            false => word
+        };
+        
+        let body = rest.trim_right_matches(|c: char| ".,:;!?".contains(c));
+        let mut chars = body.chars().rev();
+        match (chars.next(), chars.next()) {
+            (Some('"'), Some(c)) if !c.is_numeric() => {
+                out.push_str(&body[.. body.len() - 1]);
+                out.push_str(self.quote_space);
+                out.push_str(&self.quotes.1);
+                out.push_str(&rest[body.len() ..]);
+            },
+            _ => out.push_str(rest)
+        }
+        
+        match out == word {
+            true => None,
+            false => Some(out)
+        }
+    }
+
+    /// glue in front of the given punctuation
+    pub fn punctuation_glue(&self, p: &str) -> Glue {
+        match p.chars().next() {
+            Some(c) if self.spaced.contains(c) => Glue::thin_nbspace(),
+            _ => Glue::None
+        }
+    }
+
+    /// split off trailing punctuation that needs a space in front:
+    /// `vrai?` -> (`vrai`, `?`)
+    pub fn split_spaced<'a>(&self, word: &'a str) -> Option<(&'a str, &'a str)> {
+        let spaced = self.spaced;
+        let pos = word.trim_right_matches(|c: char| spaced.contains(c)).len();
+        if pos > 0 && pos < word.len() {
+            Some(word.split_at(pos))
+        } else {
+            None
+        }
+    }
+
+    /// the replacement for `items[n]`, if any rule matched
+    pub fn apply(&self, items: &[Item], n: usize) -> Option<IString> {
+        let (class, text) = match items[n] {
+            Item::Word(ref s) => (Class::Word, s),
+            Item::Punctuation(ref s) => (Class::Punctuation, s),
+            Item::Symbol(ref s) => (Class::Symbol, s),
+            _ => return None
+        };
+        let numeric = n > 0 && is_number(items.get(n-1)) && is_number(items.get(n+1));
+
+        let mut out: Option<String> = None;
+        for rule in self.rules.iter().filter(|r| r.class == class) {
+            if rule.context == Context::Numeric && !numeric {
+                continue;
+            }
+
+            let replaced = {
+                let current: &str = match out {
+                    Some(ref s) => s,
+                    None => text
+                };
+                match class {
+                    Class::Word if current.contains(&*rule.pattern) =>
+                        Some(current.replace(&*rule.pattern, &rule.replace)),
+                    Class::Punctuation | Class::Symbol if current == &*rule.pattern =>
+                        Some(rule.replace.to_string()),
+                    _ => None
+                }
+            };
+            if replaced.is_some() {
+                out = replaced;
+            }
+        }
+
+        if class == Class::Word {
+            let quoted = {
+                let current: &str = match out {
+                    Some(ref s) => s,
+                    None => text
+                };
+                self.replace_quotes(current)
+            };
+            if quoted.is_some() {
+                out = quoted;
+            }
+        }
+
+        out.map(|s| s.into())
+    }
+}
+
+#[test]
+fn test_quotes() {
+    let en = Language::by_name("en").unwrap();
+    assert_eq!(en.replace_quotes("\"foo").as_ref().map(|s| s.as_str()), Some("“foo"));
+    assert_eq!(en.replace_quotes("bar\",").as_ref().map(|s| s.as_str()), Some("bar”,"));
+    assert_eq!(en.replace_quotes("\"foo\"").as_ref().map(|s| s.as_str()), Some("“foo”"));
+    assert_eq!(en.replace_quotes("5\""), None);
+    assert_eq!(en.replace_quotes("\""), None);
+    
+    let fr = Language::by_name("fr").unwrap();
+    assert_eq!(fr.replace_quotes("\"vrai\".").as_ref().map(|s| s.as_str()), Some("«\u{202F}vrai\u{202F}»."));
+}
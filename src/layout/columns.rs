@@ -4,14 +4,246 @@ use num::Zero;
 //use layout::style::{Style};
 use units::Length;
 use std::fmt::{self, Debug};
+use std::f32;
 
-#[derive(Copy, Clone, Debug, Default)]
+/// Parameters of the line breaking, in the spirit of TeX.
+/// Demerits are subtracted from the score of a layout, `factor²` for each line.
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct BreakParams {
+    /// multiplies the penalty of hyphenated breaks (`\hyphenpenalty`)
+    pub hyphen_penalty:     f32,
+    
+    /// demerits for two hyphenated lines in a row (`\doublehyphendemerits`)
+    pub double_hyphen:      f32,
+    
+    /// demerits for a hyphen at the end of the second-to-last line (`\finalhyphendemerits`)
+    pub final_hyphen:       f32,
+    
+    /// demerits for a line much looser or tighter than the one before (`\adjdemerits`)
+    pub adjacent_fitness:   f32,
+    
+    /// Positive values favour layouts with more lines, negative ones fewer (`\looseness`).
+    /// Unlike TeX, no particular number of lines is aimed at:
+    /// each line earns a bonus of `looseness` times `LOOSENESS_WEIGHT`,
+    /// so a layout with more lines only wins if its lines are not much worse.
+    pub looseness:          i32,
+    
+    /// Lines stretched by a larger factor are only taken when nothing fits otherwise (`\tolerance`).
+    /// Unlimited by default.
+    pub tolerance:          f32,
+    
+    /// stretch added to each line when nothing fits otherwise (`\emergencystretch`)
+    pub emergency_stretch:  Length
+}
+impl Default for BreakParams {
+    fn default() -> BreakParams {
+        BreakParams {
+            hyphen_penalty:     1.0,
+            double_hyphen:      1.0,
+            final_hyphen:       0.5,
+            adjacent_fitness:   0.5,
+            looseness:          0,
+            tolerance:          f32::INFINITY,
+            emergency_stretch:  20.0
+        }
+    }
+}
+
+/// Parameters of the column breaking.
+/// Penalties are subtracted from the score of a layout.
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct ColumnParams {
+    /// penalties for one or two lines of a paragraph at the end of a column
+    pub orphans:        [f32; 2],
+    
+    /// penalties for one or two lines of a paragraph at the start of a column
+    pub widows:         [f32; 2],
+    
+    /// penalty for an empty column. Less filled columns cost proportionally.
+    pub fill:           f32,
+    
+    /// Paragraphs are only split if at least this many lines stay on either side.
+    /// 0 allows any split.
+    pub keep_together:  usize
+}
+impl Default for ColumnParams {
+    fn default() -> ColumnParams {
+        ColumnParams {
+            orphans:        [20.0, 2.0],
+            widows:         [20.0, 2.0],
+            fill:           10.0,
+            keep_together:  0
+        }
+    }
+}
+
+/// A line that could not be set well.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub column: usize,
+    pub line:   usize,  // within the column
+    pub kind:   Problem
+}
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Problem {
+    /// wider than the column, by the given length, even at full shrink
+    Overfull(Length),
+    
+    /// stretched beyond the stretch of its spaces, by the given factor
+    Underfull(f32)
+}
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "column {}, line {}: ", self.column + 1, self.line + 1)?;
+        match self.kind {
+            Problem::Overfull(by) => write!(f, "overfull by {:.1}", by),
+            Problem::Underfull(factor) => write!(f, "underfull (stretched {:.1}×)", factor)
+        }
+    }
+}
+
+// score for a line that does not fit, when nothing else does
+const EMERGENCY_PENALTY: f32 = 1000.0;
+
+// Score per line and unit of looseness.
+// About the demerits of a line stretched to 70%, a fairly loose line.
+const LOOSENESS_WEIGHT: f32 = 0.5;
+
+// TeX's fitness classes: tight, decent, loose and very loose
+const DECENT: u8 = 1;
+fn fitness(factor: f32) -> u8 {
+    if factor < -0.5 {
+        0
+    } else if factor <= 0.5 {
+        DECENT
+    } else if factor <= 1.0 {
+        2
+    } else {
+        3
+    }
+}
+
+// Parameters by the position they start at, see `Entry::Params`.
+// The first ones start at 0.
+#[derive(Debug)]
+struct ParamList<T: Copy> {
+    list:   Vec<(usize, T)>
+}
+impl<T: Copy> ParamList<T> {
+    fn new(initial: T) -> ParamList<T> {
+        ParamList { list: vec![(0, initial)] }
+    }
+    fn push(&mut self, pos: usize, params: T) {
+        match self.list.last_mut() {
+            Some(last) if last.0 == pos => last.1 = params,
+            _ => self.list.push((pos, params))
+        }
+    }
+    
+    // the ones in effect at `pos`
+    fn at(&self, pos: usize) -> T {
+        match self.list.binary_search_by_key(&pos, |&(start, _)| start) {
+            Ok(i) => self.list[i].1,
+            Err(i) => self.list[i - 1].1
+        }
+    }
+}
+
+/// The branches taken on a line, one bit for each branch taken (1) or not (0).
+/// Up to 64 branches fit in `Short`. Longer paths are kept in `BranchPaths`,
+/// until no line break uses them anymore (see `ParagraphLayout::drop_paths`).
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum BranchPath {
+    Short(u64),
+    Long(usize) // index into BranchPaths
+}
+impl Default for BranchPath {
+    fn default() -> BranchPath {
+        BranchPath::Short(0)
+    }
+}
+
+#[derive(Debug, Default)]
+struct BranchPaths {
+    long:   Vec<Vec<u64>>,
+    
+    // number of paths at which unused ones are dropped
+    limit:  usize
+}
+impl BranchPaths {
+    /// `path` with branch `n` taken
+    fn take(&mut self, path: BranchPath, n: usize) -> BranchPath {
+        match path {
+            BranchPath::Short(bits) if n < 64 => BranchPath::Short(bits | (1 << n)),
+            _ => {
+                // paths share prefixes, so the words are copied
+                let mut words = match path {
+                    BranchPath::Short(bits) => vec![bits],
+                    BranchPath::Long(i) => self.long[i].clone()
+                };
+                if words.len() <= n / 64 {
+                    words.resize(n / 64 + 1, 0);
+                }
+                words[n / 64] |= 1 << (n % 64);
+                
+                self.long.push(words);
+                BranchPath::Long(self.long.len() - 1)
+            }
+        }
+    }
+    
+    fn taken(&self, path: BranchPath, n: usize) -> bool {
+        match path {
+            BranchPath::Short(bits) => n < 64 && bits & (1 << n) != 0,
+            BranchPath::Long(i) => match self.long[i].get(n / 64) {
+                Some(&word) => word & (1 << (n % 64)) != 0,
+                None => false
+            }
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
 struct LineBreak {
     prev:   usize, // index to previous line-break
-    path:   u64, // one bit for each branch taken (1) or not (0)
+    path:   BranchPath,
     factor: f32,
     score:  f32,
     height: f32,
+    
+    // the line ends with a hyphen
+    hyphenated: bool,
+    fitness:    u8,
+    
+    // width beyond the column, only in emergencies
+    overfull:   Length
+}
+impl Default for LineBreak {
+    fn default() -> LineBreak {
+        LineBreak {
+            prev:       0,
+            path:       BranchPath::default(),
+            factor:     0.0,
+            score:      0.0,
+            height:     0.0,
+            hyphenated: false,
+            fitness:    DECENT,
+            overfull:   0.0
+        }
+    }
+}
+impl LineBreak {
+    fn problem(&self) -> Option<Problem> {
+        if self.overfull > 0.0 {
+            Some(Problem::Overfull(self.overfull))
+        } else if self.factor > 1.0 {
+            Some(Problem::Underfull(self.factor))
+        } else {
+            None
+        }
+    }
 }
 
 #[derive(Copy, Clone, Debug, Default)]
@@ -30,12 +262,18 @@ pub struct ParagraphLayout<'o, O: Output + 'o> {
     items:      &'o [Entry<O>],
     nodes:      Vec<Option<LineBreak>>,
     width:      Length,
-    last:       usize
+    last:       usize,
+    params:     ParamList<BreakParams>,
+    paths:      BranchPaths,
+    
+    // overfull lines are allowed
+    emergency:  bool
 }
 pub struct ColumnLayout<'o, O: Output + 'o> {
     para:       ParagraphLayout<'o, O>,
     nodes_col:  Vec<Option<ColumnBreak>>,
-    height:     Length
+    height:     Length,
+    params:     ParamList<ColumnParams>
 }
 impl<'o, O: Output + 'o> Debug for ColumnLayout<'o, O> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -49,24 +287,28 @@ impl<'o, O: Output + 'o> Debug for ParagraphLayout<'o, O> {
 }
 
 struct Context {
+    params:     BreakParams, // of the line
     measure:    FlexMeasure,
-    path:       u64,    // one bit for each branch on this line
+    path:       BranchPath, // one bit for each branch on this line
     begin:      usize,  // begin of line or branch
     pos:        usize,  // calculation starts here
     score:      f32,    // score at pos
-    branches:   u8,     // number of branches so far (<= 64)
-    punctuaton: FlexMeasure
+    branches:   usize,  // number of branches so far
+    punctuaton: FlexMeasure,
+    flagged:    bool    // a hyphen was taken on this line
 }
 impl Context {
-    fn new(start: usize, score: f32) -> Context {
+    fn new(start: usize, score: f32, params: BreakParams) -> Context {
         Context {
+            params:     params,
             measure:    FlexMeasure::zero(),
-            path:       0,
+            path:       BranchPath::default(),
             begin:      start,
             pos:        start,
             branches:   0,
             score:      score,
-            punctuaton: FlexMeasure::zero()
+            punctuaton: FlexMeasure::zero(),
+            flagged:    false
         }
     }
     fn add_word(&mut self, measure: FlexMeasure) {
@@ -88,43 +330,102 @@ impl Context {
 
 impl<'o, O: Output+Debug> ParagraphLayout<'o, O> {
     pub fn new(items: &'o [Entry<O>], width: Length) -> ParagraphLayout<'o, O> {
+        ParagraphLayout::with_params(items, width, BreakParams::default())
+    }
+    /// `params` apply until the stream says otherwise, see `Entry::Params`.
+    pub fn with_params(items: &'o [Entry<O>], width: Length, params: BreakParams) -> ParagraphLayout<'o, O> {
+        let mut layout = ParagraphLayout::prepare(items, width, params, |_, _| {});
+        layout.run();
+        layout
+    }
+    
+    // `f` is called with the column parameters in the stream
+    fn prepare<F>(items: &'o [Entry<O>], width: Length, params: BreakParams, mut f: F) -> ParagraphLayout<'o, O>
+    where F: FnMut(usize, ColumnParams)
+    {
         let limit = items.len();
         let mut nodes = vec![None; limit+1];
         nodes[0] = Some(LineBreak::default());
+        
+        let mut breaks = ParamList::new(params);
+        for (n, e) in items.iter().enumerate() {
+            if let Entry::Params(b, c) = *e {
+                breaks.push(n, b);
+                f(n, c);
+            }
+        }
 
-        let mut layout = ParagraphLayout {
+        ParagraphLayout {
             nodes,
             items,
             width,
-            last: 0
-        };
-        layout.run();
-        layout
+            last: 0,
+            params: breaks,
+            paths: BranchPaths::default(),
+            emergency: false
+        }
     }
     fn run(&mut self) {
-        let mut last = 0;
+        let mut furthest = 0;
         for start in 0 .. self.items.len() {
-            match self.nodes[start] {
-                Some(b) => {
-                    last = self.complete_line(
-                        start,
-                        Context::new(start, b.score)
-                    );
-                },
-                None => {}
+            furthest = self.lines_from(start, furthest);
+        }
+        self.last = furthest;
+    }
+    
+    // Finds the lines starting at `start`, and returns the furthest break found so far.
+    // If no break after `start` can be reached, the emergency pass allows
+    // lines looser than `tolerance` (with `emergency_stretch`), overfull lines
+    // and branches that are a last resort. It takes every break it reaches,
+    // and the end of the stream is one, so the layout always gets to the end.
+    fn lines_from(&mut self, start: usize, furthest: usize) -> usize {
+        let b = match self.nodes[start] {
+            Some(b) => b,
+            None => return furthest
+        };
+        
+        let params = self.params.at(start);
+        let mut reached = self.complete_line(start, Context::new(start, b.score, params));
+        if furthest.max(reached) <= start {
+            self.emergency = true;
+            reached = self.complete_line(start, Context::new(start, b.score, params));
+            self.emergency = false;
+        }
+        
+        // most paths belong to lines that were not kept
+        if self.paths.long.len() > self.paths.limit {
+            self.drop_paths();
+        }
+        furthest.max(reached)
+    }
+    
+    // Drops the long paths no line break uses.
+    fn drop_paths(&mut self) {
+        let mut used = vec![]; // (path, break)
+        for (n, b) in self.nodes.iter().enumerate() {
+            if let Some(LineBreak { path: BranchPath::Long(i), .. }) = *b {
+                used.push((i, n));
             }
         }
-
-        if self.nodes[last].is_none() {
-            for i in 0 .. last {
-                println!("{:3} {:?}", i, self.items[i]);
-                if let Some(b) = self.nodes[i] {
-                    println!("     {:?}", b);
-                }
+        used.sort();
+        
+        // lines on the same branches share their path
+        let mut kept = 0;
+        let mut last = None;
+        for (i, n) in used {
+            if last != Some(i) {
+                self.paths.long.swap(kept, i);
+                kept += 1;
+                last = Some(i);
+            }
+            if let Some(ref mut b) = self.nodes[n] {
+                b.path = BranchPath::Long(kept - 1);
             }
         }
-
-        self.last = last;
+        self.paths.long.truncate(kept);
+        
+        // collect again once there are as many paths again
+        self.paths.limit = 2 * kept + 64;
     }
 
     fn complete_line(&mut self, start: usize, mut c: Context) -> usize {
@@ -139,9 +440,15 @@ impl<'o, O: Output+Debug> ParagraphLayout<'o, O> {
                     if breaking {
                         // breaking case:
                         // width is not added yet!
-                        if self.maybe_update(&c, n+1) {
+                        if self.maybe_update(&c, n+1, false) {
                             last = n+1;
                         }
+                        
+                        // Only in an emergency does an overfull line get this far.
+                        // It ends here, or every way through the rest of the paragraph is tried.
+                        if c.measure.shrink > self.width {
+                            break;
+                        }
                     }
                     
                     // add width now.
@@ -152,18 +459,21 @@ impl<'o, O: Output+Debug> ParagraphLayout<'o, O> {
                         c.fill(self.width);
                     }
                     
-                    if self.maybe_update(&c, n+1) {
+                    // hyphens break the line too, but only the last one ends the paragraph
+                    let end = self.ends_paragraph(n+1);
+                    if self.maybe_update(&c, n+1, end) {
                         last = n+1;
                     }
                     break;
                 },
                 Entry::BranchEntry(len) => {
                     // b
+                    let path = self.paths.take(c.path, c.branches);
                     let b_last = self.complete_line(
                         start,
                         Context {
                             pos:        n + 1,
-                            path:       c.path | (1 << c.branches),
+                            path:       path,
                             branches:   c.branches + 1,
                             ..          c
                         }
@@ -179,60 +489,126 @@ impl<'o, O: Output+Debug> ParagraphLayout<'o, O> {
                 Entry::BranchExit(skip) => {
                     c.pos += skip;
                 }
+                Entry::LastResort if !self.emergency => {
+                    return last;
+                },
+                Entry::Penalty(amount, flagged) => {
+                    if flagged {
+                        c.score -= amount * c.params.hyphen_penalty;
+                        c.flagged = true;
+                    } else {
+                        c.score -= amount;
+                    }
+                },
                 _ => {}
             }
             
-            if c.measure.shrink > self.width {
+            if c.measure.shrink > self.width && !self.emergency {
                 break; // too full
             }
             
             c.pos += 1;
         }
         
+        // the stream ended without a line break
+        if c.pos == self.items.len() && c.begin < c.pos {
+            if self.maybe_update(&c, c.pos, true) {
+                last = c.pos;
+            }
+        }
+        
         last
     }
 
-    fn maybe_update(&mut self, c: &Context, n: usize) -> bool {
-        if let Some(factor) = c.line().factor(self.width) {
-            let break_score = c.score - factor * factor;
-            let break_point = LineBreak {
-                prev:   c.begin,
-                path:   c.path,
-                factor: factor,
-                score:  break_score,
-                height: c.measure.height
-            };
-            self.nodes[n] = Some(match self.nodes[n] {
-                Some(line) if break_score <= line.score => line,
-                _ => break_point
-            });
-
-            true
-        } else {
-            false
+    // the break at `n` ends a paragraph, or the stream
+    fn ends_paragraph(&self, n: usize) -> bool {
+        n == self.items.len() || match self.items[n - 1] {
+            Entry::Linebreak(fill) => fill,
+            _ => false
         }
     }
+    
+    // `end`: the paragraph ends here
+    fn maybe_update(&mut self, c: &Context, n: usize, end: bool) -> bool {
+        let mut measure = c.line();
+        if self.emergency {
+            measure.stretch += c.params.emergency_stretch;
+        }
+        let (factor, overfull) = match measure.factor(self.width) {
+            // too loose, unless the paragraph ends anyway
+            Some(factor) if factor > c.params.tolerance && !end && !self.emergency => return false,
+            Some(factor) => (factor, 0.0),
+            None if self.emergency => (-1.0, measure.shrink - self.width),
+            None => return false
+        };
+        
+        let prev = self.nodes[c.begin].unwrap_or_default();
+        let fitness = fitness(factor);
+        
+        let params = c.params;
+        let mut demerits = factor * factor
+            - params.looseness as f32 * LOOSENESS_WEIGHT;
+        if c.flagged && prev.hyphenated {
+            demerits += params.double_hyphen;
+        }
+        if end && prev.hyphenated {
+            demerits += params.final_hyphen;
+        }
+        if (fitness as i32 - prev.fitness as i32).abs() > 1 {
+            demerits += params.adjacent_fitness;
+        }
+        if overfull > 0.0 {
+            demerits += EMERGENCY_PENALTY + overfull;
+        }
+        
+        let break_score = c.score - demerits;
+        let break_point = LineBreak {
+            prev:   c.begin,
+            path:   c.path,
+            factor: factor,
+            score:  break_score,
+            height: c.measure.height,
+            
+            // the next paragraph starts afresh
+            hyphenated: c.flagged && !end,
+            fitness:    if end { DECENT } else { fitness },
+            overfull:   overfull
+        };
+        self.nodes[n] = Some(match self.nodes[n] {
+            Some(line) if break_score <= line.score => line,
+            _ => break_point
+        });
+        
+        true
+    }
     pub fn lines<'l>(&'l self) -> Column<'l, 'o, O> {
         Column::new(0, self.last, self)
     }
+    
+    /// The lines that are overfull or underfull.
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        diagnostics(self, 0, &self.lines().lines)
+    }
 }
 impl<'a, O: Output+Debug> ColumnLayout<'a, O>  {
     pub fn new(items: &'a StreamVec<O>, width: Length, height: Length) -> ColumnLayout<'a, O> {
-        let limit = items.len();
-        let mut nodes = vec![None; limit+1];
-        let mut nodes_col = vec![None; limit+1];
-        nodes[0] = Some(LineBreak::default());
+        ColumnLayout::with_params(items, width, height, BreakParams::default(), ColumnParams::default())
+    }
+    /// `params` and `columns` apply until the stream says otherwise, see `Entry::Params`.
+    pub fn with_params(items: &'a StreamVec<O>, width: Length, height: Length,
+        params: BreakParams, columns: ColumnParams) -> ColumnLayout<'a, O>
+    {
+        let mut nodes_col = vec![None; items.len()+1];
         nodes_col[0] = Some(ColumnBreak::default());
+        
+        let mut column_params = ParamList::new(columns);
+        let para = ParagraphLayout::prepare(items, width, params, |n, c| column_params.push(n, c));
 
         let mut layout = ColumnLayout {
-            para: ParagraphLayout {
-                nodes,
-                items,
-                width,
-                last: 0
-            },
+            para,
             nodes_col,
             height,
+            params: column_params
         };
         layout.run();
         layout
@@ -240,49 +616,59 @@ impl<'a, O: Output+Debug> ColumnLayout<'a, O>  {
     pub fn columns<'l>(&'l self) -> Columns<'l, 'a, O> {
         Columns::new(self)
     }
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        let mut out = vec![];
+        for (n, column) in self.columns().enumerate() {
+            out.extend(diagnostics(&self.para, n, &column.lines));
+        }
+        out
+    }
     fn run(&mut self) {
-        let mut last = 0;
+        let mut furthest = 0;
         for start in 0 .. self.para.items.len() {
-            match self.para.nodes[start] {
-                Some(b) => {
-                    last = self.para.complete_line(
-                        start,
-                        Context::new(start, b.score)
-                    );
-                    self.compute_column(start, false);
-                },
-                None => {}
-            }
-        }
-        self.compute_column(last, true);
-
-        if self.nodes_col[last].is_none() {
-            for i in 0 .. last {
-                println!("{:3} {:?}", i, self.para.items[i]);
-                if let Some(b) = self.para.nodes[i] {
-                    println!("     {:?}", b);
-                }
-                if let Some(l) = self.nodes_col[i] {
-                    println!("     {:?}", l);
-                }
+            furthest = self.para.lines_from(start, furthest);
+            if self.para.nodes[start].is_some() {
+                self.compute_column(start, false);
             }
         }
+        self.compute_column(furthest, true);
 
-        self.para.last = last;
+        self.para.last = furthest;
     }
 
-    fn num_lines_penalty(&self, n: usize) -> f32 {
+    fn num_lines_penalty(penalties: &[f32; 2], n: usize) -> f32 {
         match n {
-            1 => -20.0,
-            2 => -2.0,
+            1 => -penalties[0],
+            2 => -penalties[1],
             _ => 0.0
         }
     }
-    fn fill_penalty(&self, fill: Length) -> f32 {
-        -10.0 * (self.height - fill) / self.height
+    fn fill_penalty(&self, params: &ColumnParams, fill: Length) -> f32 {
+        -params.fill * (self.height - fill) / self.height
+    }
+    
+    // a paragraph split with too few lines on one side
+    fn torn_apart(params: &ColumnParams, orphans: usize, widows: usize) -> bool {
+        let k = params.keep_together;
+        (orphans > 0 && orphans < k) || (widows > 0 && widows < k)
+    }
+    
+    // the line ending at `n` has to stay in one column with the next
+    fn keep_with_next(&self, n: usize) -> bool {
+        let start = match self.para.nodes[n] {
+            Some(b) => b.prev,
+            None => return false
+        };
+        self.para.items[start .. n].iter().any(|e| match *e {
+            Entry::KeepWithNext => true,
+            _ => false
+        })
     }
 
     fn compute_column(&mut self, n: usize, is_last: bool) -> bool {
+        let keep = !is_last && n > 0 && self.keep_with_next(n);
+        let column_params = self.params.at(n);
+        
         //                                        measure:
         let mut num_lines_before_end = 0;      // - lines before the break; reset between paragraphs
         let mut num_lines_at_last_break = 0;   // - lines after the previous break; count until the last paragraph starts
@@ -292,22 +678,22 @@ impl<'a, O: Output+Debug> ColumnLayout<'a, O>  {
         let mut found = false;
         
         loop {
-            let last_node = self.para.nodes[last].unwrap();
+            let last_node = match self.para.nodes[last] {
+                Some(b) => b,
+                None => break
+            };
                         
             if last > 0 {
-                match self.para.items[last-1] {
-                    Entry::Linebreak(_) => {
-                        is_last_paragraph = false;
-                        num_lines_before_end = 0;
-                    },
-                    Entry::Space { .. } => {
-                        num_lines_before_end += 1;
-
-                        if is_last_paragraph {
-                            num_lines_at_last_break += 1;
-                        }
+                if self.para.ends_paragraph(last) {
+                    is_last_paragraph = false;
+                    num_lines_before_end = 0;
+                } else {
+                    // a space or a hyphen
+                    num_lines_before_end += 1;
+                    
+                    if is_last_paragraph {
+                        num_lines_at_last_break += 1;
                     }
-                    ref e => panic!("found: {:?}", e)
                 }
                 
                 height += last_node.height;
@@ -317,26 +703,37 @@ impl<'a, O: Output+Debug> ColumnLayout<'a, O>  {
                 }
             }
 
-            if let Some(column) = self.nodes_col[last] {
-                let mut score = column.score
-                    + self.num_lines_penalty(num_lines_at_last_break)
-                    + self.num_lines_penalty(num_lines_before_end);
-                
-                if !is_last {
-                    score += self.fill_penalty(height);
-                }
+            // the start of the document does not split a paragraph
+            let orphans = num_lines_at_last_break;
+            let widows = if last == 0 { 0 } else { num_lines_before_end };
             
-                match self.nodes_col[n] {
-                    Some(column) if column.score > score => {},
-                    _ => {
-                        self.nodes_col[n] = Some(ColumnBreak {
-                            prev: last,
-                            score: score
-                        });
-                        
-                        found = true;
+            // those of the paragraph that is split
+            let params = self.params.at(last);
+            
+            match self.nodes_col[last] {
+                Some(_) if keep || Self::torn_apart(&params, orphans, widows) => {},
+                Some(column) => {
+                    let mut score = column.score
+                        + Self::num_lines_penalty(&params.orphans, orphans)
+                        + Self::num_lines_penalty(&params.widows, widows);
+                    
+                    if !is_last {
+                        score += self.fill_penalty(&column_params, height);
                     }
-                }
+                
+                    match self.nodes_col[n] {
+                        Some(column) if column.score > score => {},
+                        _ => {
+                            self.nodes_col[n] = Some(ColumnBreak {
+                                prev: last,
+                                score: score
+                            });
+                            
+                            found = true;
+                        }
+                    }
+                },
+                None => {}
             }
 
             if last == 0 {
@@ -345,6 +742,21 @@ impl<'a, O: Output+Debug> ColumnLayout<'a, O>  {
             last = last_node.prev;
         }
         
+        // Nothing fits. Put the line in a column of its own,
+        // so every line can end a column and the layout is always complete.
+        if !found && self.nodes_col[n].is_none() && n > 0 {
+            let prev = match self.para.nodes[n] {
+                Some(b) => b.prev,
+                None => return false
+            };
+            if let Some(column) = self.nodes_col[prev] {
+                self.nodes_col[n] = Some(ColumnBreak {
+                    prev:   prev,
+                    score:  column.score - EMERGENCY_PENALTY
+                });
+            }
+        }
+        
         found
     }
 }
@@ -352,15 +764,20 @@ impl<'a, O: Output+Debug> ColumnLayout<'a, O>  {
 #[derive(Debug)]
 pub struct Columns<'l, 'o: 'l, O: Output + 'o> {
     layout:     &'l ColumnLayout<'o, O>,
-    columns:    Vec<usize>
+    columns:    Vec<(usize, usize)> // first and last break
 }
 impl<'l, 'o: 'l, O: Output + 'o> Columns<'l, 'o, O> {
     fn new(layout: &'l ColumnLayout<'o, O>) -> Self {
         let mut columns = Vec::new();
         let mut last = layout.para.last;
         while last > 0 {
-            columns.push(last);
-            last = layout.nodes_col[last].unwrap().prev;
+            // `compute_column` gives every line a column, but nothing is dropped if not
+            let prev = match layout.nodes_col[last] {
+                Some(column) => column.prev,
+                None => layout.para.nodes[last].map(|b| b.prev).unwrap_or(0)
+            };
+            columns.push((prev, last));
+            last = prev;
         }
         Columns {
             layout: layout,
@@ -372,8 +789,8 @@ impl<'l, 'o: 'l, O: Output + 'o> Iterator for Columns<'l, 'o, O> {
     type Item = Column<'l, 'o, O>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.columns.pop().map(|last| Column::new(
-            self.layout.nodes_col[last].unwrap().prev,
+        self.columns.pop().map(|(first, last)| Column::new(
+            first,
             last,
             &self.layout.para
         ))
@@ -401,6 +818,18 @@ impl<'l, 'o: 'l, O: Output + 'o> Column<'l, 'o, O> {
         }
     }
 }
+
+fn diagnostics<O: Output>(para: &ParagraphLayout<O>, column: usize, lines: &[usize]) -> Vec<Diagnostic> {
+    // lines are stored last to first
+    lines.iter().rev().enumerate()
+        .filter_map(|(line, &end)| para.nodes[end].and_then(|b| b.problem()).map(|kind| Diagnostic {
+            column: column,
+            line:   line,
+            kind:   kind
+        }))
+        .collect()
+}
+
 impl<'l, 'o: 'l, O: Output + 'o> Iterator for Column<'l, 'o, O> {
     type Item = (Length, Line<'l, 'o, O>);
     
@@ -448,7 +877,7 @@ impl<'l, 'o: 'l, O: Output + 'o> Iterator for Line<'l, 'o, O> {
                     self.measure += s;
                 },
                 Entry::BranchEntry(len) => {
-                    if self.line.path & (1<<self.branches) == 0 {
+                    if !self.layout.paths.taken(self.line.path, self.branches) {
                         // not taken
                         self.pos += len;
                     }
@@ -460,6 +889,10 @@ impl<'l, 'o: 'l, O: Output + 'o> Iterator for Line<'l, 'o, O> {
                     self.measure.at(self.line.factor),
                     Item::Anchor(&*data)
                 )),
+                Entry::Mark(ref mark) => return Some((
+                    self.measure.at(self.line.factor),
+                    Item::Mark(mark)
+                )),
                 _ => {}
             }
         }
@@ -467,3 +900,297 @@ impl<'l, 'o: 'l, O: Output + 'o> Iterator for Line<'l, 'o, O> {
         None
     }
 }
+
+#[test]
+fn test_branch_path() {
+    let mut paths = BranchPaths::default();
+    let mut path = BranchPath::default();
+    for &n in [0, 5, 63, 64, 200].iter() {
+        path = paths.take(path, n);
+    }
+    
+    for n in 0 .. 256 {
+        assert_eq!(paths.taken(path, n), [0, 5, 63, 64, 200].contains(&n));
+    }
+    assert_eq!(paths.take(BranchPath::default(), 7), BranchPath::Short(1 << 7));
+}
+
+// words are as wide as they say, spaces do not occur
+/// Words are lengths, for tests of the layout.
+#[cfg(test)]
+#[derive(Debug)]
+pub struct TestOutput {
+    style:  ::layout::Style<TestOutput>
+}
+#[cfg(test)]
+impl TestOutput {
+    pub fn new() -> TestOutput {
+        TestOutput {
+            style: ::layout::Style {
+                font:       (),
+                font_size:  1.0,
+                leading:    1.0,
+                par_indent: 0.0,
+                breaks:     BreakParams::default(),
+                columns:    ColumnParams::default()
+            }
+        }
+    }
+}
+#[cfg(test)]
+pub struct TestSurface;
+#[cfg(test)]
+impl ::layout::Surface for TestSurface {
+    fn primary(&self) -> Length { 0.0 }
+    fn secondary(&self) -> Option<Length> { None }
+}
+#[cfg(test)]
+impl Output for TestOutput {
+    type Word = Length;
+    type Font = ();
+    type UnscaledFont = ();
+    type Surface = TestSurface;
+    
+    fn measure(_: &(), s: &str) -> Length { s.len() as Length }
+    fn use_font_data(&self, _: ::io::Data) {}
+    fn scale(&self, _: &(), _: Length) {}
+    fn measure_word(w: &Length, _: Length) -> FlexMeasure {
+        FlexMeasure { height: 1.0, .. FlexMeasure::fixed(*w) }
+    }
+    fn measure_space(_: &(), _: ::units::Scale) -> FlexMeasure {
+        FlexMeasure { shrink: 0.5, width: 1.0, stretch: 2.0, height: 0.0 }
+    }
+    fn draw_word(_: &mut TestSurface, _: ::units::Point, _: &Length) {}
+    fn style(&self, _: &str) -> Option<&::layout::Style<TestOutput>> { Some(&self.style) }
+    fn surface(&self, _: ::units::Size) -> TestSurface { TestSurface }
+}
+
+#[test]
+fn test_double_hyphen() {
+    // three lines of one word each, the first two end with a hyphen or another penalty
+    let lines = |second_hyphenated| vec![
+        Entry::<TestOutput>::Word(10.0),
+        Entry::Penalty(0.5, true),
+        Entry::Linebreak(false),
+        Entry::Word(10.0),
+        Entry::Penalty(0.5, second_hyphenated),
+        Entry::Linebreak(false),
+        Entry::Word(10.0),
+        Entry::Linebreak(true)
+    ];
+    let score = |items: &[Entry<TestOutput>]| {
+        let layout = ParagraphLayout::new(items, 10.0);
+        assert_eq!(layout.lines().lines.len(), 3);
+        layout.nodes[layout.last].unwrap().score
+    };
+    
+    let params = BreakParams::default();
+    let once = score(&lines(false)[..]);
+    let twice = score(&lines(true)[..]);
+    
+    // the second hyphen follows the first, and the last line follows a hyphen
+    let expected = params.double_hyphen + params.final_hyphen;
+    assert!((once - twice - expected).abs() < 1e-4, "{} - {} != {}", once, twice, expected);
+}
+
+#[test]
+fn test_params_in_stream() {
+    let mut strict = BreakParams::default();
+    strict.hyphen_penalty = 10.0;
+    
+    let score = |items: &[Entry<TestOutput>]| {
+        let layout = ParagraphLayout::new(items, 10.0);
+        layout.nodes[layout.last].unwrap().score
+    };
+    let plain = score(&[
+        Entry::Word(10.0),
+        Entry::Penalty(0.5, true),
+        Entry::Linebreak(false),
+        Entry::Word(10.0),
+        Entry::Linebreak(true)
+    ]);
+    let styled = score(&[
+        Entry::Params(strict, ColumnParams::default()),
+        Entry::Word(10.0),
+        Entry::Penalty(0.5, true),
+        Entry::Linebreak(false),
+        Entry::Word(10.0),
+        Entry::Linebreak(true)
+    ]);
+    assert!((plain - styled - 0.5 * 9.0).abs() < 1e-4);
+}
+
+#[test]
+fn test_keep_heading() {
+    use layout::{GenericWriter, Writer, Atom, Glue};
+    
+    // a paragraph, a heading and two more, one line each
+    let output = TestOutput::new();
+    let mut w = GenericWriter::new(&output);
+    for (n, &text) in ["text", "Title", "body", "more"].iter().enumerate() {
+        if n == 1 {
+            w.keep_with_next();
+        }
+        w.word(Atom::normal(text));
+        w.promote(Glue::hfill());
+    }
+    let items = w.into_stream();
+    
+    // it ends up on the line of the heading, not the one before
+    let ends: Vec<usize> = items.iter().enumerate()
+        .filter_map(|(n, e)| match *e { Entry::Linebreak(_) => Some(n + 1), _ => None })
+        .collect();
+    let keep = items.iter().position(|e| match *e { Entry::KeepWithNext => true, _ => false }).unwrap();
+    assert!(ends[0] <= keep && keep < ends[1]);
+    
+    // two lines fit in a column, but not the first two
+    let layout = ColumnLayout::new(&items, 10.0, 2.0);
+    let columns = layout.columns().columns;
+    assert!(columns.iter().all(|&(_, last)| last != ends[1]));
+}
+
+#[test]
+fn test_too_wide() {
+    use layout::{GenericWriter, Writer, Atom, Glue};
+    
+    // nothing helps, the line is overfull
+    let items = [Entry::<TestOutput>::Word(30.0), Entry::Linebreak(true)];
+    let layout = ParagraphLayout::new(&items, 10.0);
+    assert_eq!(layout.diagnostics(), vec![Diagnostic {
+        column: 0,
+        line:   0,
+        kind:   Problem::Overfull(20.0)
+    }]);
+    
+    // a word of 20 that may be split in the middle
+    let output = TestOutput::new();
+    let mut w = GenericWriter::new(&output);
+    w.branch(&mut |b| {
+        b.add(&mut |w: &mut Writer| w.word(Atom::normal("aaaaaaaaaaaaaaaaaaaa")));
+        b.add(&mut |w: &mut Writer| {
+            w.last_resort();
+            w.penalty(100.0, false);
+            w.word(Atom { left: Glue::space(), right: Glue::newline(), text: "aaaaaaaaaa" });
+            w.word(Atom { left: Glue::newline(), right: Glue::space(), text: "aaaaaaaaaa" });
+        });
+    });
+    w.promote(Glue::hfill());
+    let items = w.into_stream();
+    let split = items.iter().position(|e| match *e { Entry::Linebreak(false) => true, _ => false }).unwrap() + 1;
+    
+    // it is split when it does not fit
+    let layout = ParagraphLayout::new(&items, 10.0);
+    assert_eq!(layout.lines().lines, vec![items.len(), split]);
+    assert_eq!(layout.diagnostics(), vec![]);
+    
+    // and not even tried when it does
+    let layout = ParagraphLayout::new(&items, 20.0);
+    assert_eq!(layout.lines().lines, vec![items.len()]);
+    assert!(layout.nodes[split].is_none());
+}
+
+#[test]
+fn test_never_truncated() {
+    // no line break at the end, and a word that does not fit
+    let items = vec![
+        Entry::<TestOutput>::Word(10.0),
+        Entry::Space(true, FlexMeasure::fixed(1.0)),
+        Entry::Word(30.0)
+    ];
+    let layout = ParagraphLayout::new(&items, 10.0);
+    assert_eq!(layout.lines().lines, vec![3, 2]);
+    assert_eq!(layout.diagnostics(), vec![Diagnostic {
+        column: 0,
+        line:   1,
+        kind:   Problem::Overfull(20.0)
+    }]);
+    
+    // one line per column
+    let layout = ColumnLayout::new(&items, 10.0, 1.0);
+    assert_eq!(layout.columns().count(), 2);
+    assert_eq!(layout.diagnostics(), vec![Diagnostic {
+        column: 1,
+        line:   0,
+        kind:   Problem::Overfull(20.0)
+    }]);
+}
+
+#[test]
+fn test_emergency_many_hyphens() {
+    use layout::{GenericWriter, Writer, Atom, Glue};
+    
+    // forty words with a hyphen each, and one wider than the column in the middle
+    let output = TestOutput::new();
+    let mut w = GenericWriter::new(&output);
+    for n in 0 .. 40 {
+        if n == 20 {
+            w.word(Atom::normal("xxxxxxxxxxxxxxxxxxxxxxxxxxxxxx"));
+        }
+        w.branch(&mut |b| {
+            b.add(&mut |w: &mut Writer| w.word(Atom::normal("abcdef")));
+            b.add(&mut |w: &mut Writer| {
+                w.penalty(0.5, true);
+                w.word(Atom { left: Glue::space(), right: Glue::None, text: "abc" });
+                w.punctuation(Atom { left: Glue::None, right: Glue::newline(), text: "-" });
+                w.word(Atom { left: Glue::newline(), right: Glue::space(), text: "def" });
+            });
+        });
+    }
+    w.promote(Glue::hfill());
+    let items = w.into_stream();
+    
+    let layout = ParagraphLayout::new(&items, 20.0);
+    assert_eq!(layout.last, items.len());
+    let overfull: Vec<_> = layout.diagnostics().into_iter()
+        .filter(|d| match d.kind { Problem::Overfull(_) => true, _ => false })
+        .collect();
+    assert_eq!(overfull.len(), 1);
+}
+
+#[test]
+fn test_long_paths_dropped() {
+    use layout::{GenericWriter, Writer, Atom, Glue};
+    
+    // a hundred words with a hyphen each on one line, so most paths are long
+    let output = TestOutput::new();
+    let mut w = GenericWriter::new(&output);
+    for _ in 0 .. 100 {
+        w.branch(&mut |b| {
+            b.add(&mut |w: &mut Writer| w.word(Atom::normal("abcdef")));
+            b.add(&mut |w: &mut Writer| {
+                w.penalty(0.5, true);
+                w.word(Atom { left: Glue::space(), right: Glue::None, text: "abc" });
+                w.punctuation(Atom { left: Glue::None, right: Glue::newline(), text: "-" });
+                w.word(Atom { left: Glue::newline(), right: Glue::space(), text: "def" });
+            });
+        });
+    }
+    w.promote(Glue::hfill());
+    let items = w.into_stream();
+    
+    let mut layout = ParagraphLayout::new(&items, 1000.0);
+    assert_eq!(layout.last, items.len());
+    
+    // they grow with the breaks, not with the branches tried
+    let breaks = layout.nodes.iter().filter(|b| b.is_some()).count();
+    assert!(layout.paths.long.len() <= 2 * breaks + 64);
+    
+    // and are dropped once no break uses them
+    let used = layout.nodes.iter().filter(|b| match **b {
+        Some(LineBreak { path: BranchPath::Long(_), .. }) => true,
+        _ => false
+    }).count();
+    assert!(used > 0);
+    layout.drop_paths();
+    assert!(layout.paths.long.len() <= used);
+    
+    // the lines still take the same branches
+    let words = |layout: &ParagraphLayout<TestOutput>| -> Vec<Length> {
+        layout.lines().flat_map(|(_, line)| line.filter_map(|(_, item)| match item {
+            Item::Word(&w) => Some(w),
+            _ => None
+        })).collect()
+    };
+    let before = words(&ParagraphLayout::new(&items, 1000.0));
+    assert_eq!(words(&layout), before);
+}
@@ -5,18 +5,30 @@ use layout::style::{Style};
 
 struct GenericBranchGen<'a, O: Output + 'a> {
     parent: &'a GenericWriter<'a, O>,
-    branches: Vec<(StreamVec<O>, Glue)>
+    branches: Vec<(StreamVec<O>, Glue, Keep)>
 }
 impl<'a, O: Output + 'a> BranchGenerator<'a> for GenericBranchGen<'a, O> {
     fn add(&mut self, f: &mut FnMut(&mut Writer)) {
         let mut w = self.parent.dup();
         f(&mut w);
-        self.branches.push((w.stream, w.state));
+        self.branches.push((w.stream, w.state, w.keep));
     }
 }
+
+// where `keep_with_next` is in effect
+#[derive(Copy, Clone, PartialEq)]
+enum Keep {
+    Not,
+    // asked for, but the line it is meant for has not started yet
+    Asked,
+    // the current line
+    Line
+}
+
 pub struct GenericWriter<'a, O: Output + 'a> {
     state:      Glue,
     stream:     StreamVec<O>,
+    keep:       Keep,
     style:      &'a Style<O>,
     output:     &'a O
 }
@@ -56,6 +68,7 @@ impl<'a, O: Output + 'a> GenericWriter<'a, O> {
         GenericWriter {
             state:  Glue::None,
             stream: Vec::new(),
+            keep:   Keep::Not,
             style:  output.style("default").expect("no default style"),
             output: output
         }
@@ -72,6 +85,12 @@ impl<'a, O: Output + 'a> GenericWriter<'a, O> {
         &self.stream
     }
     
+    /// like `finish`, but keeps the stream
+    pub fn into_stream(mut self) -> StreamVec<O> {
+        self.write_glue(Glue::Newline { fill: false });
+        self.stream
+    }
+    
     fn push_branch<I>(&mut self, mut ways: I) where I: Iterator<Item=StreamVec<O>> {
         if let Some(default) = ways.next() {
             let mut others: Vec<StreamVec<O>> = ways.collect();
@@ -101,6 +120,10 @@ impl<'a, O: Output + 'a> GenericWriter<'a, O> {
     fn write_glue(&mut self, left: Glue) {
         match self.state | left {
             Glue::Newline { fill: f } => {
+                if self.keep == Keep::Line {
+                    self.stream.push(Entry::KeepWithNext);
+                    self.keep = Keep::Not;
+                }
                 self.stream.push(Entry::Linebreak(f));
                 if self.style.par_indent != 0.0 {
                     self.stream.push(Entry::Space(false, FlexMeasure::fixed(self.style.par_indent)));
@@ -112,11 +135,22 @@ impl<'a, O: Output + 'a> GenericWriter<'a, O> {
         }
     }
     
+    // the line breaking has to know when the parameters change
+    fn set_style(&mut self, style: &'a Style<O>) {
+        if style.breaks != self.style.breaks || style.columns != self.style.columns {
+            self.stream.push(Entry::Params(style.breaks, style.columns));
+        }
+        self.style = style;
+    }
+    
     #[inline(always)]
     fn push<F>(&mut self, left: Glue, right: Glue, f: F) where
     F: FnOnce(&mut StreamVec<O>, &O::Font)
     {
         self.write_glue(left);
+        if self.keep == Keep::Asked {
+            self.keep = Keep::Line;
+        }
         f(&mut self.stream, self.style.font());
         
         self.state = right;
@@ -134,8 +168,12 @@ impl<'a, O: Output + 'a> Writer for GenericWriter<'a, O> {
         
             gen.branches
         };
+        // all branches start and end on the same lines
+        if let Some(&(_, _, keep)) = branches.first() {
+            self.keep = keep;
+        }
         let mut glue = Glue::any();
-        self.push_branch(branches.drain(..).map(|(v, s)| {
+        self.push_branch(branches.drain(..).map(|(v, s, _)| {
             glue |= s;
             v
         }));
@@ -169,6 +207,25 @@ impl<'a, O: Output + 'a> Writer for GenericWriter<'a, O> {
         self.stream.push(Entry::Anchor(w.stream));
     }
     
+    fn mark(&mut self, mark: Mark) {
+        // bypass glue
+        self.stream.push(Entry::Mark(mark));
+    }
+    
+    fn penalty(&mut self, amount: f32, flagged: bool) {
+        self.stream.push(Entry::Penalty(amount, flagged));
+    }
+    
+    fn last_resort(&mut self) {
+        self.stream.push(Entry::LastResort);
+    }
+    
+    fn keep_with_next(&mut self) {
+        // The previous line may not have ended yet (see `write_glue`),
+        // so it is written once the next line ends.
+        self.keep = Keep::Asked;
+    }
+    
     #[inline(always)]
     fn promote(&mut self, glue: Glue) {
         self.state |= glue;
@@ -179,14 +236,15 @@ impl<'a, O: Output + 'a> Writer for GenericWriter<'a, O> {
         body: &mut FnMut(&mut Writer)
     ) {
         let old_style = self.style;
+        let output = self.output;
         
-        self.style = self.output.style_or_default(name);
+        self.set_style(output.style_or_default(name));
         head(self);
         
-        self.style = self.output.style_or_default(&format!("{}.body", name));
+        self.set_style(output.style_or_default(&format!("{}.body", name)));
         body(self);
         
-        self.style = old_style;
+        self.set_style(old_style);
     }
 }
  
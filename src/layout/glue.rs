@@ -12,6 +12,9 @@ pub enum Glue {
         fill:       bool
     }
 }
+// scale of a thin space
+const THIN: f32 = 0.5;
+
 fn combine_scale(left: f32, right: f32) -> f32 {
     if left > right {
         left
@@ -35,6 +38,11 @@ impl BitOr for Glue {
                 panic!("Newline and NonBreaking requested");
             },
             
+            // a thin NonBreaking space stays thin (French punctuation)
+            (Space { breaking: false, scale: a }, Space { breaking: true, .. }) |
+            (Space { breaking: true, .. }, Space { breaking: false, scale: a }) if a == THIN
+             => Space { breaking: false, scale: a },
+            
             // NonBreaking wins over Breaking
             (Space { breaking: false, scale: a }, Space { breaking: true,  scale: b }) |
             (Space { breaking: true,  scale: a }, Space { breaking: false, scale: b })
@@ -69,6 +77,10 @@ impl Glue {
     pub fn nbspace() -> Glue {
         Glue::Space { breaking: false, scale: 1.0 }
     }
+    /// a non-breaking space of half the width, as used in French typography
+    pub fn thin_nbspace() -> Glue {
+        Glue::Space { breaking: false, scale: THIN }
+    }
     pub fn newline() -> Glue {
         Glue::Newline { fill: false }
     }
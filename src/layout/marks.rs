@@ -0,0 +1,118 @@
+use std::collections::BTreeMap;
+use istring::IString;
+use output::Output;
+use layout::{Columns, Item};
+
+/// Something that wants to know where it ended up.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Mark {
+    /// an entry of the keyword index
+    Keyword(IString),
+    
+    /// an entry of the table of contents
+    Heading(u8, IString)
+}
+
+/// Where a mark ended up.
+/// This is the page (column) number for paged outputs
+/// and the number of the anchor for HTML.
+pub type Location = usize;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TocEntry {
+    pub level:      u8,
+    pub title:      IString,
+    pub location:   Location
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexEntry {
+    pub name:       IString,
+
+    // sorted, no duplicates
+    pub locations:  Vec<Location>
+}
+
+/// The marks found in one layout pass.
+/// They are used in the following pass to fill in indices and references.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Marks {
+    // sort key -> entry
+    index:  BTreeMap<String, IndexEntry>,
+    
+    // in document order
+    toc:    Vec<TocEntry>
+}
+impl Marks {
+    pub fn new() -> Marks {
+        Marks::default()
+    }
+
+    pub fn add(&mut self, mark: &Mark, loc: Location) {
+        match *mark {
+            Mark::Keyword(ref name) => {
+                let entry = self.index.entry(sort_key(name))
+                .or_insert_with(|| IndexEntry {
+                    name:       name.clone(),
+                    locations:  vec![]
+                });
+                if let Err(i) = entry.locations.binary_search(&loc) {
+                    entry.locations.insert(i, loc);
+                }
+            },
+            Mark::Heading(level, ref title) => {
+                self.toc.push(TocEntry {
+                    level:      level,
+                    title:      title.clone(),
+                    location:   loc
+                });
+            }
+        }
+    }
+
+    /// collect the marks of a finished layout.
+    /// Columns are counted from 1.
+    pub fn collect<'l, 'o: 'l, O: Output + 'o>(columns: Columns<'l, 'o, O>) -> Marks {
+        let mut marks = Marks::new();
+        for (n, column) in columns.enumerate() {
+            for (_, line) in column {
+                for (_, item) in line {
+                    if let Item::Mark(mark) = item {
+                        marks.add(mark, n + 1);
+                    }
+                }
+            }
+        }
+        marks
+    }
+
+    pub fn toc(&self) -> &[TocEntry] {
+        &self.toc
+    }
+
+    /// The index entries in sorted order, grouped by their initial.
+    pub fn index(&self) -> Vec<(char, Vec<&IndexEntry>)> {
+        let mut groups: Vec<(char, Vec<&IndexEntry>)> = Vec::new();
+
+        for (key, entry) in self.index.iter() {
+            let initial = key.chars().next()
+                .and_then(|c| c.to_uppercase().next())
+                .unwrap_or(' ');
+
+            match groups.last_mut() {
+                Some(&mut (c, ref mut entries)) if c == initial => {
+                    entries.push(entry);
+                    continue;
+                },
+                _ => {}
+            }
+            groups.push((initial, vec![entry]));
+        }
+
+        groups
+    }
+}
+
+fn sort_key(name: &str) -> String {
+    name.to_lowercase()
+}
@@ -1,5 +1,5 @@
 use std::fmt::{self, Debug};
-use std::rc::Rc;
+use shared::{Shared, MaybeSync};
 use units::*;
 use output::Output;
 
@@ -9,6 +9,8 @@ mod glue;
 mod generic_writer;
 mod flex;
 mod style;
+mod marks;
+mod text;
 pub mod columns;
 
 pub use self::glue::Glue;
@@ -17,6 +19,8 @@ pub use self::generic_writer::{GenericWriter};
 pub use self::flex::FlexMeasure;
 pub use self::style::Style;
 pub use self::columns::*;
+pub use self::marks::{Mark, Marks, Location, IndexEntry, TocEntry};
+pub use self::text::TextWriter;
 
 // to flex or not to flex?
 #[allow(unused_variables)]
@@ -73,17 +77,37 @@ pub enum Entry<O: Output> {
     /// items to skip.
     BranchExit(usize),
     
-    Style(Rc<Style<O>>),
+    Style(Shared<Style<O>>),
     
     /// a reference to something.
     /// location can be queried, once the main layout is complete
-    Anchor(StreamVec<O>)
+    Anchor(StreamVec<O>),
+    
+    /// records where the mark ended up. Has no width.
+    Mark(Mark),
+    
+    /// Makes breaking the line after this point worse, by the given amount.
+    /// Used in branches, like hyphenated words.
+    /// Flagged penalties are hyphens, see `BreakParams`.
+    Penalty(f32, bool),
+    
+    /// The current line has to end up in the same column as the next one.
+    /// Written on the line a heading starts on.
+    KeepWithNext,
+    
+    /// The style changed. Its parameters apply from the next line on.
+    Params(BreakParams, ColumnParams),
+    
+    /// The branch it is in is only taken if nothing else fits.
+    /// Used for words split without a hyphen.
+    LastResort
 }
 
 /// result of the linebreaking algorithm
 pub enum Item<'o, O: Output + 'o> {
     Word(&'o O::Word),
-    Anchor(&'o [Entry<O>])
+    Anchor(&'o [Entry<O>]),
+    Mark(&'o Mark)
 }
 
 pub type StreamVec<O> = Vec<Entry<O>>;
@@ -114,7 +138,7 @@ pub trait BranchGenerator<'a> {
     fn add(&mut self, &mut FnMut(&mut Writer));
 }
 
-pub trait Object: Debug {
+pub trait Object: Debug + MaybeSync {
     fn measure(&self, primary: Length) -> FlexMeasure;
     fn show(&self, out: &mut Surface);
     fn glue(&self) -> (Glue, Glue);
@@ -147,6 +171,24 @@ pub trait Writer {
     );
     
     fn anchor(&mut self, content: &mut FnMut(&mut Writer)) {}
+    
+    // record the position of the mark
+    fn mark(&mut self, _mark: Mark) {}
+    
+    // breaking the current line after this point is worse
+    // flagged: the break is a hyphen
+    fn penalty(&mut self, _amount: f32, _flagged: bool) {}
+    
+    // do not break the column after the next line that is written
+    fn keep_with_next(&mut self) {}
+    
+    // only take the current branch if nothing else fits
+    fn last_resort(&mut self) {}
+    
+    // refer to a location that was collected in a previous pass
+    fn reference(&mut self, loc: Location) {
+        self.word(Atom::normal(&loc.to_string()));
+    }
 }
 
 pub trait Surface {
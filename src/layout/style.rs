@@ -1,11 +1,14 @@
 use output::Output;
+use layout::{BreakParams, ColumnParams};
 
 #[derive(Debug)]
 pub struct Style<O: Output> {
     pub font: O::Font,
     pub font_size: f32,
     pub leading: f32,
-    pub par_indent: f32
+    pub par_indent: f32,
+    pub breaks: BreakParams,
+    pub columns: ColumnParams
 }
 impl<O: Output> Style<O> {
     pub fn font(&self) -> &O::Font {
@@ -0,0 +1,71 @@
+use layout::*;
+
+struct TextBranchGen<'a> {
+    w:      &'a mut TextWriter,
+    first:  bool
+}
+impl<'a> BranchGenerator<'a> for TextBranchGen<'a> {
+    fn add(&mut self, f: &mut FnMut(&mut Writer)) {
+        if self.first {
+            self.first = false;
+            f(self.w);
+        }
+    }
+}
+
+/// Collects the plain text of whatever is written.
+/// Used where a node is needed as a string (keywords, titles).
+pub struct TextWriter {
+    state:  Glue,
+    text:   String
+}
+impl TextWriter {
+    pub fn new() -> TextWriter {
+        TextWriter {
+            state:  Glue::None,
+            text:   String::new()
+        }
+    }
+    pub fn into_string(self) -> String {
+        self.text
+    }
+
+    fn push(&mut self, atom: Atom) {
+        if self.text.len() > 0 {
+            match self.state | atom.left {
+                Glue::None => {},
+                _ => self.text.push(' ')
+            }
+        }
+        self.text.push_str(atom.text);
+        self.state = atom.right;
+    }
+}
+impl Writer for TextWriter {
+    fn word(&mut self, word: Atom) {
+        self.push(word);
+    }
+
+    fn punctuation(&mut self, p: Atom) {
+        self.push(p);
+    }
+
+    fn branch(&mut self, f: &mut FnMut(&mut BranchGenerator)) {
+        f(&mut TextBranchGen {
+            w:      self,
+            first:  true
+        });
+    }
+
+    fn promote(&mut self, glue: Glue) {
+        self.state |= glue;
+    }
+
+    fn with(&mut self, _name: &str,
+        head: &mut FnMut(&mut Writer),
+        body: &mut FnMut(&mut Writer)
+    ) {
+        head(self);
+        body(self);
+    }
+}
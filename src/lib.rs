@@ -25,6 +25,8 @@ extern crate woot;
 extern crate futures_await as futures;
 extern crate istring;
 extern crate indexmap;
+#[cfg(feature="sync")]
+extern crate crossbeam;
 extern crate num;
 extern crate tuple;
 
@@ -48,7 +50,10 @@ pub mod slug;
 pub mod nodes;
 pub mod environment;
 pub mod document;
+pub mod encoding;
 pub mod hyphenation;
+pub mod patterns;
+pub mod language;
 pub mod layout;
 pub mod parser;
 pub mod io;
@@ -58,6 +63,9 @@ pub mod units;
 pub mod config;
 pub mod book;
 pub mod source;
+pub mod query;
+pub mod json;
+pub mod shared;
 
 use wheel::prelude::*;
 use istring::IString;
@@ -69,7 +77,10 @@ pub enum LoomError {
     DirectoryOpen(<Directory as AsyncOpen>::Error),
     ConfigError(config::ParseError),
     MissingArg(&'static str),
+    InvalidArg(&'static str),
     Hyphenator(fst::Error),
+    Decode(encoding::DecodeError),
+    Json(serde_json::Error),
     MissingItem(IString),
     Parser
 }
@@ -10,9 +10,21 @@ impl Aside {
             inner: inner
         }
     }
+    pub fn decode(d: &mut Decoder) -> Result<NodeP, DecodeError> {
+        Ok(Ptr::new(Aside::new(d.node()?)).into())
+    }
 }
 
 impl Node for Aside {
+    fn type_name(&self) -> &'static str {
+        "aside"
+    }
+    fn encode(&self, e: &mut Encoder) {
+        e.node(&self.inner);
+    }
+    fn to_json(&self, j: &mut JsonWriter) -> JsonNode {
+        JsonNode::Aside { inner: j.shared(&self.inner) }
+    }
     fn childs(&self, out: &mut Vec<NodeP>) {
         self.inner.childs(out);
     }
@@ -6,7 +6,9 @@ pub struct Block {
     
     env: LocalEnv,
     
-    fields: Fields
+    fields: Fields,
+    
+    span: Span
 }
 
 impl Block {
@@ -18,50 +20,160 @@ impl Block {
         let argument = block.argument;
         let body = block.body;
         let name = block.name.to_string();
+        let span = block.span;
         let childs = body.childs;
         
         box init_env(io.clone(), env.clone(), body.commands, body.parameters)
         .and_then(move |env| {
             let args = Ptr::new(
                 NodeList::from(&io2,
-                    argument.into_iter().map(|n| item_node(&io2, &env, n))
+                    item_nodes(&io2, &env, argument).into_iter()
                 )
             );
             
             process_body(io2, env, childs)
             .map(|(env, body)| -> NodeP {
-                let p = Ptr::new(Block {
+                let block = Block {
                     target:     Ref::new(name).resolve(&env),
                     env:        env.take(),
                     fields:     Fields {
                         args:   Some(args),
                         body:   Some(body)
-                    }
-                });
-                p.into()
+                    },
+                    span:       span
+                };
+                if let Some(ref target) = block.target.get() {
+                    block.check(target);
+                }
+                
+                let p: NodeP = Ptr::new(block).into();
+                if let Some(target) = p.target() {
+                    target.add_ref(&p);
+                }
+                p
             })
         })
     }
 }
 
+impl Block {
+    /// The target is resolved later, see `Yarn::resolve`.
+    pub fn new(name: String, env: LocalEnv, fields: Fields, span: Span) -> Block {
+        Block {
+            target:     Ref::new(name),
+            env:        env,
+            fields:     fields,
+            span:       span
+        }
+    }
+    
+    /// Warn if the arguments do not match what the macro `target` takes.
+    pub fn check(&self, target: &NodeP) {
+        if let Some(msg) = self.arity_problem(target) {
+            warn!(Log::root(), "{}", msg);
+        }
+    }
+    
+    /// what `check` warns about, with the places of the block and the macro
+    pub fn arity_problem(&self, target: &NodeP) -> Option<String> {
+        let sig = match target.signature() {
+            Some(sig) => sig,
+            None => return None
+        };
+        let given = self.fields.args.as_ref().map(|a| a.size()).unwrap_or(0);
+        sig.check(given).map(|problem| format!("{}: :{} {} (defined at {})",
+            self.span, self.target.name(), problem,
+            target.span().unwrap_or_default()
+        ))
+    }
+    
+    pub fn decode(d: &mut Decoder) -> Result<NodeP, DecodeError> {
+        let target = Ref::decode(d)?;
+        let env = LocalEnv::decode(d)?;
+        let fields = Fields::decode(d)?;
+        Ok(Ptr::new(Block {
+            target:     target,
+            env:        env,
+            fields:     fields,
+            span:       d.span()?
+        }).into())
+    }
+}
+
 impl Node for Block {
+    fn type_name(&self) -> &'static str {
+        "block"
+    }
+    fn encode(&self, e: &mut Encoder) {
+        self.target.encode(e);
+        self.env.encode(e);
+        self.fields.encode(e);
+        e.span(&self.span);
+    }
+    fn to_json(&self, j: &mut JsonWriter) -> JsonNode {
+        JsonNode::Block {
+            name:   self.target.name().to_string(),
+            env:    self.env.to_json(j),
+            args:   self.fields.args.as_ref().map(|l| j.list(l)),
+            body:   self.fields.body.as_ref().map(|l| j.list(l)),
+            span:   (&self.span).into()
+        }
+    }
     fn childs(&self, out: &mut Vec<NodeP>) {
         self.env.childs(out);
         self.fields.childs(out);
     }
-    fn layout(&self, env: LayoutChain, w: &mut Writer) {
+    fn layout(&self, caller: LayoutChain, w: &mut Writer) {
         if let Some(ref target) = self.target.get() {
-            target.layout(env.link(self), w);
+            let env = caller.link(self);
+            
+            // the block itself, or the macro it invokes may ask for a toc entry
+            let toc = self.env.toc_level()
+                .or_else(|| target.env().and_then(|e| e.toc_level()));
+            
+            if let Some(level) = toc {
+                let mut title = TextWriter::new();
+                if let Some(ref args) = self.fields.args {
+                    args.layout(caller.clone(), &mut title);
+                }
+                w.mark(Mark::Heading(level, title.into_string().into()));
+                
+                // a heading never ends a column
+                w.keep_with_next();
+            }
+            
+            let exp = match Expansion::new(&caller, self.target.name(), target) {
+                Ok(exp) => exp,
+                Err(chain) => {
+                    error!(Log::root(), "recursive macro: {}", chain);
+                    error_marker(w, &["recursive", "macro", self.target.name()]);
+                    return;
+                }
+            };
+            target.layout(env.expand(&exp), w);
         } else {
             warn!(Log::root(), "unresolved name: {}", self.target.name());
-            for s in &["unresolved" as &str, "macro" as &str, self.target.name()] {
-                w.word(Atom {
-                    left:   Glue::space(),
-                    right:  Glue::space(),
-                    text:   s
-                });
+            error_marker(w, &["unresolved", "macro", self.target.name()]);
+        }
+    }
+    fn modified(&self) {
+        // the arguments or the macro changed
+        if let Some(ref target) = self.target.get() {
+            self.check(target);
+        }
+    }
+    fn target(&self) -> Option<NodeP> {
+        self.target.get()
+    }
+    fn resolve(&self, scope: &Scope) -> bool {
+        let known = self.target.get().is_some();
+        let found = self.target.resolve_in(scope);
+        if !known {
+            if let Some(ref target) = self.target.get() {
+                self.check(target);
             }
         }
+        found
     }
     fn env(&self) -> Option<&LocalEnv> {
         Some(&self.env)
@@ -69,4 +181,43 @@ impl Node for Block {
     fn fields(&self) -> Option<&Fields> {
         Some(&self.fields)
     }
+    fn name(&self) -> Option<&str> {
+        Some(self.target.name())
+    }
+    fn span(&self) -> Option<Span> {
+        Some(self.span.clone())
+    }
+}
+
+#[test]
+fn test_arity() {
+    use io::machine;
+    
+    let io = machine();
+    let word = |s| -> NodeP { Ptr::new(Word::new(s)).into() };
+    let list = |items: Vec<NodeP>| Ptr::new(NodeList::from(&io, items.into_iter()));
+    let at = |line| Span { offset: 0, line: line, file: Some("book.yarn".into()) };
+    
+    // `/greet name` using `$name`
+    let def: NodeP = Ptr::new(Definition::new("greet".into(),
+        list(vec![word("name")]),
+        list(vec![Ptr::new(Placeholder::Argument(0)).into()]),
+        LocalEnv::new(), at(1)
+    )).into();
+    let block = |args: Vec<NodeP>| Block::new("greet".into(), LocalEnv::new(),
+        Fields { args: Some(list(args)), body: None }, at(5)
+    );
+    
+    assert_eq!(block(vec![word("you")]).arity_problem(&def), None);
+    assert_eq!(block(vec![]).arity_problem(&def).unwrap(),
+        "book.yarn:5: :greet takes 1 argument, but only 0 are given (defined at book.yarn:1)");
+    assert_eq!(block(vec![word("you"), word("all")]).arity_problem(&def).unwrap(),
+        "book.yarn:5: :greet takes 1 argument, but 2 are given (defined at book.yarn:1)");
+    
+    // `/hr` takes nothing
+    let hr: NodeP = Ptr::new(Definition::new("hr".into(),
+        list(vec![]), list(vec![word("—")]), LocalEnv::new(), at(9)
+    )).into();
+    assert_eq!(block(vec![word("foo")]).arity_problem(&hr).unwrap(),
+        "book.yarn:5: :greet takes 0 arguments, but 1 is given (defined at book.yarn:9)");
 }
@@ -9,11 +9,35 @@ pub struct Definition {
     body:       Ptr<NodeList<NodeP>>,
     
     // referencing macro invocations
-    references: RefCell<Vec<Weak<Node>>>,
+    references: Lock<Vec<Weak<Node>>>,
     
-    env:        LocalEnv
+    // computed on demand, cleared when the arguments or the body change
+    signature:  Lock<Option<Signature>>,
+    
+    env:        LocalEnv,
+    
+    span:       Span
 }
 impl Node for Definition {
+    fn type_name(&self) -> &'static str {
+        "definition"
+    }
+    fn encode(&self, e: &mut Encoder) {
+        e.str(&self.name);
+        e.node(&self.args);
+        e.node(&self.body);
+        self.env.encode(e);
+        e.span(&self.span);
+    }
+    fn to_json(&self, j: &mut JsonWriter) -> JsonNode {
+        JsonNode::Definition {
+            name:   self.name.clone(),
+            env:    self.env.to_json(j),
+            args:   j.list(&self.args),
+            body:   j.list(&self.body),
+            span:   (&self.span).into()
+        }
+    }
     fn childs(&self, out: &mut Vec<NodeP>) {
         out.push(self.args.clone().into());
         out.push(self.body.clone().into());
@@ -24,29 +48,113 @@ impl Node for Definition {
             &mut |w| self.body.layout(env.clone() /* .link(self) */, w)
         )
     }
-    fn add_ref(&self, source: &Rc<Node>) {
-        self.references.borrow_mut().push(Rc::downgrade(source));
+    fn add_ref(&self, source: &NodeP) {
+        let mut references = self.references.borrow_mut();
+        references.retain(|r| r.upgrade().is_some());
+        references.push(source.downgrade());
+    }
+    fn references(&self) -> Vec<NodeP> {
+        self.references.borrow().iter()
+        .filter_map(|r| r.upgrade())
+        .map(Ptr::from_shared)
+        .collect()
     }
     fn env(&self) -> Option<&LocalEnv> {
         Some(&self.env)
     }
+    fn name(&self) -> Option<&str> {
+        Some(&self.name)
+    }
+    fn span(&self) -> Option<Span> {
+        Some(self.span.clone())
+    }
+    fn signature(&self) -> Option<Signature> {
+        if let Some(ref sig) = *self.signature.borrow() {
+            return Some(sig.clone());
+        }
+        let sig = self.compute_signature();
+        *self.signature.borrow_mut() = Some(sig.clone());
+        Some(sig)
+    }
+    fn modified(&self) {
+        // The invocations are told afterwards and check against the new one.
+        *self.signature.borrow_mut() = None;
+    }
+}
+
+// `$name` refers to the declared parameter `name`.
+// Definitions inside the body have their own parameters.
+fn bind_params(params: &[IString], body: &mut source::BlockBody) {
+    use source::{Body, Item, Placeholder};
+    
+    fn placeholder(params: &[IString], p: &mut Placeholder) {
+        let n = match *p {
+            Placeholder::Unknown(ref name) => params.iter().position(|q| q == name),
+            _ => None
+        };
+        if let Some(n) = n {
+            *p = Placeholder::Argument(n);
+        }
+    }
+    fn items(params: &[IString], list: &mut [Item]) {
+        for i in list.iter_mut() {
+            match *i {
+                Item::Placeholder(ref mut p) => placeholder(params, p),
+                Item::Group(ref mut g) => items(params, &mut g.content),
+                _ => {}
+            }
+        }
+    }
+    
+    for c in body.childs.iter_mut() {
+        match *c {
+            Body::Leaf(ref mut i) => items(params, i),
+            Body::List(ref mut list) => {
+                for i in list.iter_mut() {
+                    items(params, i);
+                }
+            },
+            Body::Block(ref mut b) => {
+                items(params, &mut b.argument);
+                bind_params(params, &mut b.body);
+            },
+            Body::Placeholder(ref mut p) => placeholder(params, p)
+        }
+    }
 }
 
 impl Definition {
+    pub fn new(name: String, args: NodeListP, body: NodeListP, env: LocalEnv, span: Span) -> Definition {
+        Definition {
+            name:       name,
+            args:       args,
+            body:       body,
+            references: Lock::new(vec![]),
+            signature:  Lock::new(None),
+            env:        env,
+            span:       span
+        }
+    }
     pub fn from_param(io: Io, env: GraphChain, p: source::Parameter)
      -> Box<Future<Item=Definition, Error=LoomError>>
     {
         let args = p.args;
         let name = p.name.to_string();
-        let body = p.value;
+        let span = p.span;
+        let mut body = p.value;
+        
+        let params: Vec<IString> = args.iter().filter_map(|i| match *i {
+            source::Item::Word(ref s) => Some(s.clone()),
+            _ => None
+        }).collect();
+        bind_params(&params, &mut body);
         let childs = body.childs;
         
         box init_env(io.clone(), env, body.commands, body.parameters)
         .and_then(move |env| {
             let arglist = Ptr::new(
                 NodeList::from(&io,
-                    args.into_iter()
-                    .map(|n| item_node(&io, &env, n))
+                    item_nodes(&io, &env, args).into_iter()
                 )
             );
             process_body(io, env, childs)
@@ -55,13 +163,62 @@ impl Definition {
                     name:       name,
                     args:       arglist,
                     body:       childs,
-                    references: RefCell::new(vec![]),
-                    env:        env.take()
+                    references: Lock::new(vec![]),
+                    signature:  Lock::new(None),
+                    env:        env.take(),
+                    span:       span
                 })
             })
         })
     }
+    pub fn decode(d: &mut Decoder) -> Result<NodeP, DecodeError> {
+        let name = d.str()?.to_string();
+        let args = d.list()?;
+        let body = d.list()?;
+        let env = LocalEnv::decode(d)?;
+        Ok(Ptr::new(Definition {
+            name:       name,
+            args:       args,
+            body:       body,
+            references: Lock::new(vec![]),
+            signature:  Lock::new(None),
+            env:        env,
+            span:       d.span()?
+        }).into())
+    }
     pub fn name(&self) -> &str {
         &self.name
     }
+    
+    fn compute_signature(&self) -> Signature {
+        let params: Vec<IString> = self.args.items().iter()
+            .filter_map(|n| n.text().map(|s| s.into()))
+            .collect();
+        
+        // nested definitions have placeholders of their own
+        let mut variadic = false;
+        let mut used = 0;
+        query::walk(&self.body.clone().into(), &mut |n: &NodeP, _: &[NodeP]| {
+            match n.placeholder() {
+                Some(&Placeholder::Arguments) => variadic = true,
+                Some(&Placeholder::Argument(i)) => used = used.max(i + 1),
+                _ => {}
+            }
+            n.type_name() != "definition"
+        });
+        
+        let arity = match (variadic, params.len(), used) {
+            (true, _, _) => None,
+            (false, declared, used) => Some(declared.max(used))
+        };
+        Signature {
+            params: params,
+            arity:  arity
+        }
+    }
+    
+    /// the blocks and groups using this macro
+    pub fn invocations(&self) -> Vec<NodeP> {
+        self.references()
+    }
 }
@@ -2,44 +2,109 @@ use nodes::prelude::*;
 
 pub struct Group {
     target:     GroupRef,
-    fields:     Fields
+    fields:     Fields,
+    
+    // shown when there is no target
+    delimiters: (IString, IString),
+    
+    // between the delimiters and the content
+    inner:      Glue
 }
 
 impl Group {
     pub fn from(io: &Io, env: &GraphChain, g: source::Group) -> Ptr<Group> {
         let content = Ptr::new(NodeList::from(io,
-            g.content.into_iter().map(|n| item_node(io, env, n))
+            item_nodes(io, env, g.content).into_iter()
         ));
         
-        let mut g = Ptr::new(Group {
-            target:     GroupRef::new(g.opening, g.closing),
-            fields:     Fields {
-                args:   None,
-                body:   Some(content),
-            }
-        });
+        let delimiters = (g.opening.clone(), g.closing.clone());
+        let inner = Glue::None;
+        
+        let mut g = Ptr::new(Group::new(
+            GroupRef::new(g.opening, g.closing), content, delimiters, inner
+        ));
         {
             let mut gp: &mut Group = g.get_mut().unwrap();
             gp.target.resolve(env);
         }
+        if let Some(target) = g.target.get() {
+            target.add_ref(&g.clone().into());
+        }
         g
     }
+    pub fn new(target: GroupRef, content: NodeListP, delimiters: (IString, IString), inner: Glue) -> Group {
+        Group {
+            target:     target,
+            fields:     Fields {
+                args:   None,
+                body:   Some(content),
+            },
+            delimiters: delimiters,
+            inner:      inner
+        }
+    }
+    pub fn decode(d: &mut Decoder) -> Result<NodeP, DecodeError> {
+        let target = GroupRef::decode(d)?;
+        let fields = Fields::decode(d)?;
+        let opening = d.str()?;
+        let closing = d.str()?;
+        Ok(Ptr::new(Group {
+            target:     target,
+            fields:     fields,
+            delimiters: (opening, closing),
+            inner:      d.glue()?
+        }).into())
+    }
 }
 
 impl Node for Group {
+    fn type_name(&self) -> &'static str {
+        "group"
+    }
+    fn encode(&self, e: &mut Encoder) {
+        self.target.encode(e);
+        self.fields.encode(e);
+        e.str(&self.delimiters.0);
+        e.str(&self.delimiters.1);
+        e.glue(self.inner);
+    }
+    fn to_json(&self, j: &mut JsonWriter) -> JsonNode {
+        let &(ref opening, ref closing) = self.target.key();
+        JsonNode::Group {
+            opening:    opening.to_string(),
+            closing:    closing.to_string(),
+            content:    self.fields.body.as_ref().map(|l| j.list(l)).unwrap_or(vec![]),
+            delimiters: (self.delimiters.0.to_string(), self.delimiters.1.to_string()),
+            inner:      self.inner.into()
+        }
+    }
     fn childs(&self, out: &mut Vec<NodeP>) {
         self.fields.childs(out)
     }
+    fn target(&self) -> Option<NodeP> {
+        self.target.get()
+    }
+    fn resolve(&self, scope: &Scope) -> bool {
+        self.target.resolve_in(scope);
+        true
+    }
     fn layout(&self, env: LayoutChain, w: &mut Writer) {
         if let Some(target) = self.target.get() {
-            target.layout(env.with_fields(Some(&self.fields)), w)
+            let &(ref opening, _) = self.target.key();
+            let exp = match Expansion::new(&env, &**opening, &target) {
+                Ok(exp) => exp,
+                Err(chain) => {
+                    error!(Log::root(), "recursive group: {}", chain);
+                    error_marker(w, &["recursive", "group", &**opening]);
+                    return;
+                }
+            };
+            target.layout(env.expand(&exp).with_fields(Some(&self.fields)), w)
         } else {
-            let open_close = self.target.key();
-            
             w.word(Atom {
                 left:   Glue::space(),
-                right:  Glue::None,
-                text:   &open_close.0
+                right:  self.inner,
+                text:   &self.delimiters.0
             });
             
             match self.fields.body {
@@ -48,9 +113,9 @@ impl Node for Group {
             }
             
             w.word(Atom {
-                left:   Glue::None,
+                left:   self.inner,
                 right:  Glue::space(),
-                text:   &open_close.1
+                text:   &self.delimiters.1
             });
         }
     }
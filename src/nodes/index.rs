@@ -0,0 +1,85 @@
+use nodes::prelude::*;
+
+/// Marks the body as an entry of the keyword index.
+/// Meant to be bound to a group: `!group "<" keyword ">"`
+pub struct Keyword;
+
+impl Keyword {
+    pub fn decode(_d: &mut Decoder) -> Result<NodeP, DecodeError> {
+        Ok(Ptr::new(Keyword).into())
+    }
+}
+impl Node for Keyword {
+    fn type_name(&self) -> &'static str {
+        "keyword"
+    }
+    fn encode(&self, _e: &mut Encoder) {}
+    fn to_json(&self, _j: &mut JsonWriter) -> JsonNode {
+        JsonNode::Keyword
+    }
+
+    fn layout(&self, env: LayoutChain, w: &mut Writer) {
+        let body = match env.fields().and_then(|f| f.body.clone()) {
+            Some(body) => body,
+            None => return
+        };
+
+        // the body belongs to the group, not to the keyword macro
+        let env = env.outside();
+        let mut text = TextWriter::new();
+        body.layout(env.clone(), &mut text);
+
+        w.mark(Mark::Keyword(text.into_string().into()));
+        body.layout(env, w);
+    }
+}
+
+/// The keyword index. Sorted and grouped by the initial letter.
+/// Empty until the marks of a previous layout pass are known.
+pub struct Index;
+
+impl Index {
+    pub fn decode(_d: &mut Decoder) -> Result<NodeP, DecodeError> {
+        Ok(Ptr::new(Index).into())
+    }
+}
+impl Node for Index {
+    fn type_name(&self) -> &'static str {
+        "index"
+    }
+    fn encode(&self, _e: &mut Encoder) {}
+    fn to_json(&self, _j: &mut JsonWriter) -> JsonNode {
+        JsonNode::Index
+    }
+
+    fn layout(&self, env: LayoutChain, w: &mut Writer) {
+        let marks = match env.marks() {
+            Some(marks) => marks,
+            None => return
+        };
+
+        for (initial, entries) in marks.index() {
+            let initial = initial.to_string();
+            w.with("index",
+                &mut |w| w.word(Atom::normal(&initial)),
+                &mut |w| {
+                    for entry in entries.iter() {
+                        w.word(Atom::normal(&entry.name));
+
+                        for (i, &loc) in entry.locations.iter().enumerate() {
+                            if i > 0 {
+                                w.punctuation(Atom {
+                                    left:   Glue::None,
+                                    right:  Glue::space(),
+                                    text:   ","
+                                });
+                            }
+                            w.reference(loc);
+                        }
+                        w.promote(Glue::hfill());
+                    }
+                }
+            );
+        }
+    }
+}
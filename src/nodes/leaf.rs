@@ -4,24 +4,46 @@ pub struct Leaf {
     content: NodeList<NodeP>
 }
 impl Leaf {
+    pub fn new(content: NodeList<NodeP>) -> Leaf {
+        Leaf {
+            content: content
+        }
+    }
     pub fn from(io: &Io, env: &GraphChain, items: Vec<source::Item>) -> Leaf {
         Leaf {
             content: NodeList::from(io,
-                items.into_iter().map(|n| item_node(io, env, n))
+                item_nodes(io, env, items).into_iter()
             )
         }
     }
+    pub fn decode(d: &mut Decoder) -> Result<NodeP, DecodeError> {
+        Ok(Ptr::new(Leaf {
+            content: NodeList::decode_items(d)?
+        }).into())
+    }
     pub fn get(&self, n: usize) -> Option<NodeP> {
-        self.content.iter().nth(n).cloned()
+        self.content.get(n)
     }
-    pub fn iter<'a>(&'a self) -> impl Iterator<Item=&'a NodeP> {
+    pub fn iter(&self) -> impl Iterator<Item=NodeP> {
         self.content.iter()
     }
 }
 impl Node for Leaf {
+    fn type_name(&self) -> &'static str {
+        "leaf"
+    }
+    fn encode(&self, e: &mut Encoder) {
+        self.content.encode_items(e);
+    }
+    fn to_json(&self, j: &mut JsonWriter) -> JsonNode {
+        JsonNode::Leaf { items: j.list(&self.content) }
+    }
     fn childs(&self, out: &mut Vec<NodeP>) {
         self.content.childs(out)
     }
+    fn list(&self) -> Option<&NodeList<NodeP>> {
+        Some(&self.content)
+    }
     fn layout(&self, env: LayoutChain, w: &mut Writer) {
         if self.content.size() > 0 {
             self.content.layout(env, w);
@@ -1,24 +1,46 @@
 use nodes::prelude::*;
 
 pub struct List {
-    items: NodeList<Ptr<Leaf>>
+    items: NodeList<NodeP>
 }
 impl List {
+    pub fn new(items: NodeList<NodeP>) -> List {
+        List {
+            items: items
+        }
+    }
     pub fn from(io: &Io, env: &GraphChain, items: Vec<Vec<source::Item>>) -> List {
         List {
             items: NodeList::from(
                 io,
-                items.into_iter().map(|i| Ptr::new(Leaf::from(io, env, i))
+                items.into_iter().map(|i| Ptr::new(Leaf::from(io, env, i)).into()
             ))
         }
     }
+    pub fn decode(d: &mut Decoder) -> Result<NodeP, DecodeError> {
+        Ok(Ptr::new(List {
+            items: NodeList::decode_items(d)?
+        }).into())
+    }
 }
 impl Node for List {
+    fn type_name(&self) -> &'static str {
+        "list"
+    }
+    fn encode(&self, e: &mut Encoder) {
+        self.items.encode_items(e);
+    }
+    fn to_json(&self, j: &mut JsonWriter) -> JsonNode {
+        JsonNode::List { items: j.list(&self.items) }
+    }
     fn childs(&self, out: &mut Vec<NodeP>) {
         self.items.childs(out)
     }
+    fn list(&self) -> Option<&NodeList<NodeP>> {
+        Some(&self.items)
+    }
     fn layout(&self, env: LayoutChain, w: &mut Writer) {
-        for item in self.items.iter() {
+        for item in self.items.items().iter() {
             w.word(Atom {
                 left:   Glue::space(),
                 right:  Glue::nbspace(),
@@ -2,15 +2,16 @@ mod aside;
 mod block;
 mod definition;
 mod group;
+mod index;
 mod leaf;
 mod list;
 mod module;
+mod toc;
 mod token;
 
 mod prelude {
     pub use document::*;
-    pub use std::cell::RefCell;
-    pub use std::rc::{Rc, Weak};
+    pub use shared::{Shared, Weak, Lock};
     pub use environment::*;
     pub use layout::*;
     pub use io::Io;
@@ -19,27 +20,75 @@ mod prelude {
     pub use futures::future::{Future, join_all, ok};
     pub use nodes::*;
     pub use istring::IString;
+    pub use encoding::{Encoder, Decoder, DecodeError};
+    pub use json::{JsonWriter, JsonReader, JsonNode};
+    pub use query;
 }
 pub use self::aside::*;
 pub use self::block::*;
 pub use self::definition::*;
 pub use self::group::*;
+pub use self::index::*;
 pub use self::leaf::*;
 pub use self::list::*;
 pub use self::module::*;
+pub use self::toc::*;
 pub use self::token::*;
 use self::prelude::*;
 
 use std::fmt;
 use commands::{CommandComplete};
 use wheel::Log;
+use encoding::DecodeFn;
 
 type NodeFuture = Box<Future<Item=NodeP, Error=LoomError>>;
 
+/// targets that are always available
+pub fn register(env: &mut LocalEnv) {
+    env.add_target("keyword".into(),    Ptr::new(Keyword).into());
+    env.add_target("index".into(),      Ptr::new(Index).into());
+    env.add_target("toc".into(),        Ptr::new(Toc).into());
+}
+
+/// decoders by `Node::type_name`
+pub fn decoder(name: &str) -> Option<DecodeFn> {
+    let f: DecodeFn = match name {
+        "aside" =>          Aside::decode,
+        "block" =>          Block::decode,
+        "definition" =>     Definition::decode,
+        "group" =>          Group::decode,
+        "index" =>          Index::decode,
+        "keyword" =>        Keyword::decode,
+        "leaf" =>           Leaf::decode,
+        "list" =>           List::decode,
+        "module" =>         Module::decode,
+        "nodelist" =>       NodeList::<NodeP>::decode,
+        "placeholder" =>    Placeholder::decode,
+        "punctuation" =>    Punctuation::decode,
+        "symbol" =>         Symbol::decode,
+        "toc" =>            Toc::decode,
+        "token" =>          Token::decode,
+        "word" =>           Word::decode,
+        _ => return None
+    };
+    Some(f)
+}
+
 fn wrap<N: Node + 'static>(node: N) -> NodeFuture {
     box ok(Ptr::new(node).into())
 }
 
+/// shown in place of content that can't be laid out
+fn error_marker(w: &mut Writer, words: &[&str]) {
+    for s in words {
+        w.word(Atom {
+            left:   Glue::space(),
+            right:  Glue::space(),
+            text:   s
+        });
+    }
+}
+
 fn process_body(io: Io, env: GraphChain, childs: Vec<source::Body>)
  -> Box< Future<Item=(GraphChain, NodeListP), Error=LoomError> >
 {
@@ -72,8 +121,23 @@ impl Word {
             content:    s.into(),
         }
     }
+    pub fn decode(d: &mut Decoder) -> Result<NodeP, DecodeError> {
+        Ok(Ptr::new(Word::new(&d.str()?)).into())
+    }
 }
 impl Node for Word {
+    fn type_name(&self) -> &'static str {
+        "word"
+    }
+    fn encode(&self, e: &mut Encoder) {
+        e.str(&self.content);
+    }
+    fn text(&self) -> Option<&str> {
+        Some(&self.content)
+    }
+    fn to_json(&self, _j: &mut JsonWriter) -> JsonNode {
+        JsonNode::Word { text: self.content.to_string() }
+    }
     fn layout(&self, env: LayoutChain, w: &mut Writer) {
         env.hyphenate(w, Atom {
             text:   &self.content,
@@ -90,19 +154,44 @@ impl fmt::Debug for Word {
 
 pub struct Punctuation {
     content:    IString,
+    
+    // Glue::None, unless the language asks for a space in front
+    left:       Glue
 }
 impl Punctuation {
     pub fn new(s: &str) -> Punctuation {
+        Punctuation::with_glue(s, Glue::None)
+    }
+    pub fn with_glue(s: &str, left: Glue) -> Punctuation {
         Punctuation {
             content:    s.into(),
+            left:       left
         }
     }
+    pub fn decode(d: &mut Decoder) -> Result<NodeP, DecodeError> {
+        let content = d.str()?;
+        let left = d.glue()?;
+        Ok(Ptr::new(Punctuation::with_glue(&content, left)).into())
+    }
 }
 impl Node for Punctuation {
+    fn type_name(&self) -> &'static str {
+        "punctuation"
+    }
+    fn encode(&self, e: &mut Encoder) {
+        e.str(&self.content);
+        e.glue(self.left);
+    }
+    fn text(&self) -> Option<&str> {
+        Some(&self.content)
+    }
+    fn to_json(&self, _j: &mut JsonWriter) -> JsonNode {
+        JsonNode::Punctuation { text: self.content.to_string(), left: self.left.into() }
+    }
     fn layout(&self, _env: LayoutChain, w: &mut Writer) {
         w.punctuation(Atom {
             text:   &self.content,
-            left:   Glue::None,
+            left:   self.left,
             right:  Glue::space()
         });
     }
@@ -127,8 +216,30 @@ impl Symbol {
             content:    s.into(),
         }
     }
+    /// without looking up !symbol replacements
+    pub fn raw(s: &str) -> Symbol {
+        Symbol {
+            content:    s.into()
+        }
+    }
+    pub fn decode(d: &mut Decoder) -> Result<NodeP, DecodeError> {
+        // the !symbol replacement was applied already
+        Ok(Ptr::new(Symbol::raw(&d.str()?)).into())
+    }
 }
 impl Node for Symbol {
+    fn type_name(&self) -> &'static str {
+        "symbol"
+    }
+    fn encode(&self, e: &mut Encoder) {
+        e.str(&self.content);
+    }
+    fn text(&self) -> Option<&str> {
+        Some(&self.content)
+    }
+    fn to_json(&self, _j: &mut JsonWriter) -> JsonNode {
+        JsonNode::Symbol { text: self.content.to_string() }
+    }
     fn layout(&self, _env: LayoutChain, w: &mut Writer) {
         w.word(Atom {
             text:   &self.content,
@@ -156,6 +267,56 @@ fn item_node(io: &Io, env: &GraphChain, i: source::Item) -> NodeP {
     }
 }
 
+/// Build the nodes of a sequence of items.
+/// The typographic rules of the current language need to see the neighbours
+/// of each item, so this has to work on the whole sequence.
+fn item_nodes(io: &Io, env: &GraphChain, items: Vec<source::Item>) -> Vec<NodeP> {
+    use source::Item;
+    
+    let language = env.get_language();
+    let replaced: Vec<Option<IString>> = match language {
+        Some(lang) => (0 .. items.len()).map(|n| lang.apply(&items, n)).collect(),
+        None => vec![]
+    };
+    
+    let mut nodes = Vec::with_capacity(items.len());
+    for (n, item) in items.into_iter().enumerate() {
+        let replacement = replaced.get(n).and_then(|r| r.clone());
+        match item {
+            Item::Word(s) => {
+                let s = replacement.unwrap_or(s);
+                
+                // punctuation that needs a space may be attached to the word
+                match language.and_then(|lang| lang.split_spaced(&s)) {
+                    Some((word, p)) => {
+                        nodes.push(Ptr::new(Word::new(word)).into());
+                        nodes.push(Ptr::new(Punctuation::with_glue(p, Glue::thin_nbspace())).into());
+                    },
+                    None => nodes.push(Ptr::new(Word::new(&s)).into())
+                }
+            },
+            Item::Punctuation(s) => {
+                let s = replacement.unwrap_or(s);
+                let left = match language {
+                    Some(lang) => lang.punctuation_glue(&s),
+                    None => Glue::None
+                };
+                nodes.push(Ptr::new(Punctuation::with_glue(&s, left)).into());
+            },
+            Item::Symbol(s) => {
+                // an explicit !symbol wins over the language rules
+                let s = match env.get_symbol(&s) {
+                    Some(_) => s,
+                    None => replacement.unwrap_or(s)
+                };
+                nodes.push(Ptr::new(Symbol::new(env, &s)).into());
+            },
+            item => nodes.push(item_node(io, env, item))
+        }
+    }
+    nodes
+}
+
 fn init_env(io: Io, env: GraphChain,
     commands: Vec<source::Command>, parameters: Vec<source::Parameter>)
  -> Box<Future<Item=GraphChain, Error=LoomError>>
@@ -6,10 +6,17 @@ pub struct Module {
     body:       NodeListP
 }
 impl Module {
+    pub fn new(env: LocalEnv, body: NodeListP) -> Module {
+        Module {
+            env:    env,
+            body:   body
+        }
+    }
+    /// `file` names the source in diagnostics
     #[async]
-    pub fn parse(io: Io, env: GraphChain, input: String) -> Result<NodeP, LoomError>
+    pub fn parse(io: Io, env: GraphChain, input: String, file: Option<IString>) -> Result<NodeP, LoomError>
     {
-        let body = source::parse(&io, &input)?;
+        let body = source::parse(&io, &input, file.as_ref().map(|f| &**f))?;
         let env = await!(init_env(io.clone(), env, body.commands, body.parameters))?;
         let (env, childs) = await!(process_body(io, env, body.childs))?;
         Ok(Ptr::new(Module {
@@ -17,8 +24,28 @@ impl Module {
             body:   childs
         }).into())
     }
+    pub fn decode(d: &mut Decoder) -> Result<NodeP, DecodeError> {
+        let env = LocalEnv::decode(d)?;
+        Ok(Ptr::new(Module {
+            env:    env,
+            body:   d.list()?
+        }).into())
+    }
 }
 impl Node for Module {
+    fn type_name(&self) -> &'static str {
+        "module"
+    }
+    fn encode(&self, e: &mut Encoder) {
+        self.env.encode(e);
+        e.node(&self.body);
+    }
+    fn to_json(&self, j: &mut JsonWriter) -> JsonNode {
+        JsonNode::Module {
+            env:    self.env.to_json(j),
+            body:   j.list(&self.body)
+        }
+    }
     fn childs(&self, out: &mut Vec<NodeP>) {
         self.env.childs(out);
         self.body.childs(out);
@@ -29,4 +56,7 @@ impl Node for Module {
     fn env(&self) -> Option<&LocalEnv> {
         Some(&self.env)
     }
+    fn list(&self) -> Option<&NodeList<NodeP>> {
+        Some(&self.body)
+    }
 }
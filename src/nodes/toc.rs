@@ -0,0 +1,43 @@
+use nodes::prelude::*;
+
+/// The table of contents.
+/// Lists every block that asked for it (`!toc level`) with its page number.
+/// Empty until the marks of a previous layout pass are known.
+pub struct Toc;
+
+impl Toc {
+    pub fn decode(_d: &mut Decoder) -> Result<NodeP, DecodeError> {
+        Ok(Ptr::new(Toc).into())
+    }
+}
+impl Node for Toc {
+    fn type_name(&self) -> &'static str {
+        "toc"
+    }
+    fn encode(&self, _e: &mut Encoder) {}
+    fn to_json(&self, _j: &mut JsonWriter) -> JsonNode {
+        JsonNode::Toc
+    }
+
+    fn layout(&self, env: LayoutChain, w: &mut Writer) {
+        let marks = match env.marks() {
+            Some(marks) => marks,
+            None => return
+        };
+
+        for entry in marks.toc() {
+            // indentation is up to the style
+            w.with(&format!("toc.{}", entry.level),
+                &mut |w| {
+                    for word in entry.title.split_whitespace() {
+                        w.word(Atom::normal(word));
+                    }
+                    w.promote(Glue::Space { breaking: true, scale: 4.0 });
+                    w.reference(entry.location);
+                    w.promote(Glue::hfill());
+                },
+                &mut |_| {}
+            );
+        }
+    }
+}
@@ -13,8 +13,35 @@ impl Token {
             _ => Token::Other(s)
         }
     }
+    pub fn decode(d: &mut Decoder) -> Result<NodeP, DecodeError> {
+        Ok(Ptr::new(Token::new(d.str()?)).into())
+    }
 }
 impl Node for Token {
+    fn type_name(&self) -> &'static str {
+        "token"
+    }
+    fn encode(&self, e: &mut Encoder) {
+        match *self {
+            Token::HFill => e.str("hfill"),
+            Token::QuadSpace => e.str("quad"),
+            Token::Other(ref s) => e.str(s)
+        }
+    }
+    fn to_json(&self, _j: &mut JsonWriter) -> JsonNode {
+        let text = match *self {
+            Token::HFill => "hfill",
+            Token::QuadSpace => "quad",
+            Token::Other(ref s) => &**s
+        };
+        JsonNode::Token { text: text.to_string() }
+    }
+    fn text(&self) -> Option<&str> {
+        match *self {
+            Token::Other(ref s) => Some(s),
+            _ => None
+        }
+    }
     fn layout(&self, _env: LayoutChain, w: &mut Writer) {
         match *self {
             Token::HFill => {
@@ -72,6 +72,10 @@ pub struct HtmlWriter<'a, W: Write + 'a> {
     state:      Glue,
     output:     &'a HtmlOutput,
     writer:     &'a mut W,
+    
+    // marks are anchors, numbered in document order
+    marks:      Marks,
+    anchors:    usize
 }
 fn write_glue<W: Write>(w: &mut W, glue: Glue) {
     match glue {
@@ -98,11 +102,16 @@ impl<'a, W: Write + 'a> HtmlWriter<'a, W> {
         HtmlWriter {
             state:      Glue::None,
             output:     out,
-            writer:     writer
+            writer:     writer,
+            marks:      Marks::new(),
+            anchors:    0
         }
     }
-    pub fn finish(self) {
+    
+    /// returns the marks for the next pass
+    pub fn finish(self) -> Marks {
         self.writer.write(TAIL.as_bytes()).unwrap();
+        self.marks
     }
     
     fn add_glue(&mut self, glue: Glue) {
@@ -171,4 +180,14 @@ impl<'a, W: Write + 'a> Writer for HtmlWriter<'a, W> {
     fn anchor(&mut self, content: &mut FnMut(&mut Writer)) {
         content(self)
     }
+    fn mark(&mut self, mark: Mark) {
+        self.anchors += 1;
+        self.marks.add(&mark, self.anchors);
+        write!(self.writer, r#"<a id="m{}"></a>"#, self.anchors).unwrap();
+    }
+    fn reference(&mut self, loc: Location) {
+        self.add_glue(Glue::space());
+        write!(self.writer, r##"<w><a href="#m{}">{}</a></w>"##, loc, loc).unwrap();
+        self.state = Glue::space();
+    }
 }
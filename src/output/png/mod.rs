@@ -1,8 +1,7 @@
-use layout::{Flex, FlexMeasure, Surface, Style};
+use layout::{Flex, FlexMeasure, Surface, Style, BreakParams, ColumnParams};
 use image::{GrayImage, Luma, Pixel};
 use std::collections::HashMap;
-use std::rc::Rc;
-use std::cell::RefCell;
+use shared::{Shared, Lock};
 use rusttype;
 use std::fmt::{Debug, self};
 use output::Output;
@@ -15,11 +14,19 @@ use serde_json;
 use istring::IString;
 use tuple::T2;
 
-#[derive(Clone)]
 pub struct RustTypeFont {
     font:   rusttype::Font<'static>,
     scale:  rusttype::Scale,
-    cache:  RefCell<HashMap<String, RustTypeWord>>,
+    cache:  Lock<HashMap<String, RustTypeWord>>,
+}
+impl Clone for RustTypeFont {
+    fn clone(&self) -> RustTypeFont {
+        RustTypeFont {
+            font:   self.font.clone(),
+            scale:  self.scale,
+            cache:  Lock::new(self.cache.borrow().clone())
+        }
+    }
 }
 impl RustTypeFont {
     fn measure(&self, word: &str) -> RustTypeWord {
@@ -47,7 +54,7 @@ impl RustTypeFont {
         }
         
         let w = RustTypeWord {
-            inner:  Rc::new(RustTypeWordInner {
+            inner:  Shared::new(RustTypeWordInner {
                 font:       self.font.clone(),
                 scale:      self.scale,
                 glyphs:     glyph_list,
@@ -74,7 +81,7 @@ struct RustTypeWordInner {
 }
 #[derive(Clone)]
 pub struct RustTypeWord {
-    inner:  Rc<RustTypeWordInner>
+    inner:  Shared<RustTypeWordInner>
 }
 impl Debug for RustTypeWord {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -161,7 +168,9 @@ impl PngOutput {
                 font_size:  Option<f32>,
                 leading:    Option<f32>,
                 par_indent: Option<f32>,
-                font_name:  Option<String>
+                font_name:  Option<String>,
+                breaks:     Option<BreakParams>,
+                columns:    Option<ColumnParams>
             }
             
             let raw_map: HashMap<String, RawStyle> = serde_json::from_slice(&data).unwrap();
@@ -181,9 +190,9 @@ impl PngOutput {
         })
         .map(move |(raw_map, items)| {
             // translate files into fonts
-            let fonts: HashMap<String, Rc<UnscaledRustTypeFont>> = items.into_iter()
+            let fonts: HashMap<String, Shared<UnscaledRustTypeFont>> = items.into_iter()
             .map(|(font_name, data)|
-                (font_name, Rc::new(UnscaledRustTypeFont::load(data)))
+                (font_name, Shared::new(UnscaledRustTypeFont::load(data)))
             ).collect();
             
             let mut output = PngOutput {
@@ -197,7 +206,9 @@ impl PngOutput {
                 font_size:  default_size,
                 leading:    default_raw.leading.unwrap_or(default_size * 1.5),
                 font:       output.scale(default_font, default_size),
-                par_indent: default_raw.par_indent.unwrap_or(0.)
+                par_indent: default_raw.par_indent.unwrap_or(0.),
+                breaks:     default_raw.breaks.unwrap_or_default(),
+                columns:    default_raw.columns.unwrap_or_default()
             };
             
             for (name, raw) in raw_map.into_iter() {
@@ -207,7 +218,9 @@ impl PngOutput {
                     font_size:  size,
                     leading:    raw.leading.unwrap_or(default.leading),
                     font:       output.scale(font, size),
-                    par_indent: raw.par_indent.unwrap_or(default.par_indent)
+                    par_indent: raw.par_indent.unwrap_or(default.par_indent),
+                    breaks:     raw.breaks.unwrap_or(default.breaks),
+                    columns:    raw.columns.unwrap_or(default.columns)
                 };
                 output.styles.insert(name.into(), style);
             }
@@ -237,7 +250,7 @@ impl Output for PngOutput {
         RustTypeFont {
             font:   font.font.clone(),
             scale:  rusttype::Scale::uniform(size),
-            cache:  RefCell::new(HashMap::new())
+            cache:  Lock::new(HashMap::new())
         }
     }
 
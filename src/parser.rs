@@ -93,9 +93,12 @@ named!(placeholder <Placeholder>,
       | map_opt!(digit, |s: Data| {
             s.parse::<usize>().ok().map(|n| Placeholder::Argument(n))
         })
+      | map!(letter_sequence, |s: Data| Placeholder::Unknown(s.into()))
     )
 );
 
+pub const SOFT_HYPHEN: char = '\u{AD}';
+
 #[inline(always)]
 fn is_letter(c: char) -> bool {
     match c {
@@ -106,6 +109,11 @@ fn is_letter(c: char) -> bool {
     }
 }
 
+#[inline(always)]
+fn is_alphanumeric(c: char) -> bool {
+    is_letter(c) || c.is_numeric()
+}
+
 #[inline(always)]
 fn is_punctuation(c: char) -> bool {
     match c {
@@ -196,8 +204,10 @@ fn test_letter_sequence() {
     );
 }
 
+// `"` is part of the word. The language turns it into the right quote (or leaves an inch mark).
 fn word_sequence(input: Data) -> IResult<Data, Data> {
-    sequence(input, |cp| is_letter(cp), |cp| is_letter(cp) | is_punctuation(cp))
+    sequence(input, |cp| is_alphanumeric(cp) | (cp == '"'),
+        |cp| is_alphanumeric(cp) | is_punctuation(cp) | (cp == SOFT_HYPHEN) | (cp == '"'))
 }
 #[test]
 fn test_word_sequence() {
@@ -206,6 +216,9 @@ fn test_word_sequence() {
         word_sequence("h") => Done("", "h");
         word_sequence("hello» world") => Done("» world", "hello");
         word_sequence("hello\nworld") => Done("\nworld", "hello");
+        word_sequence("1984.") => Done("", "1984.");
+        word_sequence("\"hello\", world") => Done(" world", "\"hello\",");
+        word_sequence("5\" wide") => Done(" wide", "5\"");
         word_sequence("") => Error;
     );
 }
@@ -243,8 +256,44 @@ fn test_string() {
     );
 }
 
+/// `hy\-phen` may only be broken after `hy`, `\-word` not at all.
+/// Both become soft hyphens.
+fn word(input: Data) -> IResult<Data, IString> {
+    let mut out = String::new();
+    let mut rest = input;
+    
+    if let Ok((r, _)) = tag!(rest, "\\-") {
+        out.push(SOFT_HYPHEN);
+        rest = r;
+    }
+    let (r, first) = word_sequence(rest)?;
+    out.push_str(first.into());
+    rest = r;
+    
+    while let Ok((r, _)) = tag!(rest, "\\-") {
+        match word_sequence(r) {
+            Ok((r, part)) => {
+                out.push(SOFT_HYPHEN);
+                out.push_str(part.into());
+                rest = r;
+            },
+            Err(_) => break
+        }
+    }
+    done!(rest, out.into())
+}
+#[test]
+fn test_word() {
+    slug!(
+        word("hy\\-phen\\-ation ") => Done(" ", IString::from("hy\u{AD}phen\u{AD}ation"));
+        word("\\-loom\n") => Done("\n", IString::from("\u{AD}loom"));
+        word("hy\u{AD}phen") => Done("", IString::from("hy\u{AD}phen"));
+        word("\\foo") => Error;
+    );
+}
+
 named!(item_word <Item>,
-    map!(word_sequence, |s: Data<'a>| { Item::Word(s.into()) })
+    map!(word, |s: IString| { Item::Word(s) })
 );
 named!(item_symbol <Item>,
     map!(apply!(sequence, is_symbol, is_symbol),
@@ -295,10 +344,11 @@ fn item<'a>(input: Data<'a>) -> IResult<Data<'a>, Item> {
     match input.iter_elements().next() {
         Some(c) => match c {
             'a' ... 'z' |
-            'A' ... 'Z' => item_word(input),
+            'A' ... 'Z' |
+            '0' ... '9' => item_word(input),
             '.' | ',' | ':' | '!' | '?' => item_punctuation(input),
             '$' => item_placeholder(input),
-            '\\' => item_token(input),
+            '\\' => alt!(input, item_word | item_token),
             '<' | '(' | '[' | '{' => item_group(input),
             _ => alt!(input, item_word | item_group | item_symbol | item_punctuation)
         },
@@ -331,10 +381,14 @@ fn test_item() {
             content: vec![Item::Word("baä".into())],
             closing: "»".into()
         }));
+        item("\"foo bar\" baz") => Done(" bar\" baz", Item::Word("\"foo".into()));
         item("f.oo baz") => Done(" baz", Item::Word("f.oo".into()));
+        item("42 baz") => Done(" baz", Item::Word("42".into()));
         item("$body\n") => Done("\n", Item::Placeholder(Placeholder::Body));
         item("$3\n") => Done("\n", Item::Placeholder(Placeholder::Argument(3)));
+        item("$author\n") => Done("\n", Item::Placeholder(Placeholder::Unknown("author".into())));
         item("\\foo\n") => Done("\n", Item::Token("foo".into()));
+        item("\\-foo\n") => Done("\n", Item::Word("\u{AD}foo".into()));
         item("\n") => Error;
     );
 }
@@ -526,6 +580,7 @@ fn test_command() {
 
 #[inline(always)]
 pub fn pattern(input: Data, indent_level: usize) -> IResult<Data, Parameter> {
+    let span = Span::remaining(input.input_len());
     do_parse!(input,
               complete!(count!(indent_any, indent_level))
     >>        tag!("/")
@@ -534,7 +589,7 @@ pub fn pattern(input: Data, indent_level: usize) -> IResult<Data, Parameter> {
     >>  args: separated_list!(space, item)
     >>        endline
     >> value: apply!(block_body, indent_level + 1)
-    >>       (Parameter { name: name.into(), args: args, value: value })
+    >>       (Parameter { name: name.into(), args: args, value: value, span: span })
     )
 }
 
@@ -548,7 +603,8 @@ fn test_pattern_1() {
                 commands:   vec![],
                 parameters: vec![],
                 childs:     vec![],
-            }
+            },
+            span:   Span::remaining(7)
         });
     );
 }
@@ -566,7 +622,8 @@ fn test_pattern_2() {
                         Item::Word("bar".into())
                     ])
                 ]
-            }
+            },
+            span:   Span::remaining(16)
         });
     );
 }
@@ -585,7 +642,8 @@ fn test_pattern_3() {
                         Item::Placeholder(Placeholder::Argument(0))
                     ])
                 ]
-            }
+            },
+            span:   Span::remaining(19)
         });
     );
 }
@@ -611,6 +669,7 @@ named!(args <Vec<Item> >,
 
 pub fn block(input: Data, indent_level: usize) -> IResult<Data, Block> {
     //println!("block at level {}:", indent_level);
+    let span = Span::remaining(input.input_len());
     do_parse!(input,
                 complete!(count!(indent_any, indent_level))
     >>          complete!(tag!(":"))
@@ -621,7 +680,8 @@ pub fn block(input: Data, indent_level: usize) -> IResult<Data, Block> {
     >>         (Block {
                     name:       name.into(),
                     argument:   arg,
-                    body:       body
+                    body:       body,
+                    span:       span
                 })
     )
 }
@@ -639,7 +699,8 @@ fn test_block_1() {
                         Item::Word("x".into()),
                     ])
                 ]
-            }
+            },
+            span:       Span::remaining(12)
         });
     );
 }
@@ -657,7 +718,8 @@ fn test_block_2() {
                         Item::Word("x".into()),
                     ])
                 ]
-            }
+            },
+            span:       Span::remaining(13)
         });
     );
 }
@@ -680,7 +742,8 @@ fn test_block_3() {
                         Item::Word("x".into()),
                     ])
                 ]
-            }
+            },
+            span:       Span::remaining(19)
         });
         block(":foo A\n    !x\n    x\nx", 0) => Done("x", Block {
             name:       "foo".into(),
@@ -698,7 +761,8 @@ fn test_block_3() {
                         Item::Word("x".into()),
                     ])
                 ]
-            }
+            },
+            span:       Span::remaining(21)
         });
         block(":foo A\n    :bar\n    x\nx", 0) => Done("x", Block {
             name:       "foo".into(),
@@ -714,13 +778,15 @@ fn test_block_3() {
                             commands:   vec![],
                             parameters: vec![],
                             childs:     vec![]
-                        }
+                        },
+                        span:       Span::remaining(16)
                     }),
                     Body::Leaf(vec![
                         Item::Word("x".into()),
                     ])
                 ]
-            }
+            },
+            span:       Span::remaining(23)
         });
         
         block(":foo A\n    :bar\n\n    x  y\n\tz\nx", 0) => Done("x", Block {
@@ -737,7 +803,8 @@ fn test_block_3() {
                             commands:   vec![],
                             parameters: vec![],
                             childs:     vec![]
-                        }
+                        },
+                        span:       Span::remaining(16)
                     }),
                     Body::Leaf(vec![
                         Item::Word("x".into()),
@@ -745,7 +812,8 @@ fn test_block_3() {
                         Item::Word("z".into()),
                     ])
                 ]
-            }
+            },
+            span:       Span::remaining(30)
         });
     );
 }
@@ -0,0 +1,116 @@
+//! Liang's hyphenation patterns, as used by TeX.
+//!
+//! The `hyph-*.pat.txt` files of the hyph-utf8 project list one pattern per
+//! line, like `hen5at`. A digit between two letters votes for (odd) or
+//! against (even) a break there. The highest vote of all matching patterns wins.
+
+use std::collections::HashMap;
+use hyphenation::{Hyphen, Hyphens};
+
+pub struct Patterns {
+    // letters of a pattern -> votes before, between and after them
+    patterns:   HashMap<String, Vec<u8>>,
+
+    // longest pattern, in chars
+    max_len:    usize,
+
+    /// chars at the start of a word that are never split off
+    pub left_min:   usize,
+
+    /// chars at the end of a word that are never split off
+    pub right_min:  usize
+}
+
+impl Patterns {
+    /// Read a pattern file. `%` starts a comment.
+    /// A TeX `\patterns{…}` wrapper is skipped.
+    pub fn parse(text: &str) -> Patterns {
+        let mut patterns = HashMap::new();
+        let mut max_len = 0;
+
+        for line in text.lines() {
+            let line = match line.find('%') {
+                Some(n) => &line[.. n],
+                None => line
+            };
+            for token in line.split_whitespace() {
+                if token.starts_with('\\') || token == "}" {
+                    continue;
+                }
+
+                let mut letters = String::new();
+                let mut votes = vec![0];
+                for c in token.chars() {
+                    match c.to_digit(10) {
+                        Some(d) => *votes.last_mut().unwrap() = d as u8,
+                        None => {
+                            letters.push(c);
+                            votes.push(0);
+                        }
+                    }
+                }
+
+                max_len = max_len.max(votes.len() - 1);
+                patterns.insert(letters, votes);
+            }
+        }
+
+        Patterns {
+            patterns:   patterns,
+            max_len:    max_len,
+            left_min:   2,
+            right_min:  3
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.patterns.len()
+    }
+
+    /// Break points of `word`, which has to be lowercase.
+    pub fn hyphenate(&self, word: &str) -> Hyphens {
+        let chars: Vec<char> = Some('.').into_iter()
+            .chain(word.chars())
+            .chain(Some('.'))
+            .collect();
+
+        let mut votes = vec![0u8; chars.len() + 1];
+        let mut key = String::new();
+        for start in 0 .. chars.len() {
+            key.clear();
+            for end in start .. chars.len().min(start + self.max_len) {
+                key.push(chars[end]);
+                if let Some(v) = self.patterns.get(&key) {
+                    for (i, &vote) in v.iter().enumerate() {
+                        let slot = &mut votes[start + i];
+                        *slot = (*slot).max(vote);
+                    }
+                }
+            }
+        }
+
+        // votes[pos + 1] is between char pos - 1 and pos of the word (the dot comes first)
+        let len = chars.len() - 2;
+        let mut hyphens = Hyphens::default();
+        for pos in self.left_min.max(1) .. (len + 1).saturating_sub(self.right_min.max(1)) {
+            if votes[pos + 1] % 2 == 1 {
+                hyphens <<= Hyphen::at(pos);
+            }
+        }
+        hyphens
+    }
+}
+
+#[test]
+fn test_hyphenate() {
+    let p = Patterns::parse("% from the TeXbook\n.ach4 hy3ph he2n hena4 hen5at 1na n2at 1tio 2io o2n\n");
+    assert_eq!(p.len(), 10);
+
+    let mut parts: Vec<&str> = p.hyphenate("hyphenation").iter()
+        .map(|h| h.apply("hyphenation").0)
+        .collect();
+    parts.sort();
+    assert_eq!(parts, vec!["hy", "hyphen"]);
+
+    assert_eq!(p.hyphenate("na").len(), 0);
+}
@@ -0,0 +1,67 @@
+use std::collections::HashSet;
+use document::{Node, NodeP};
+
+/// Walks the document graph, see `walk`.
+pub trait Visitor {
+    /// Called for each node before its childs.
+    /// `path` holds the nodes above it, starting with the root.
+    /// Return false to skip the childs.
+    fn visit(&mut self, node: &NodeP, path: &[NodeP]) -> bool;
+}
+impl<F> Visitor for F where F: FnMut(&NodeP, &[NodeP]) -> bool {
+    fn visit(&mut self, node: &NodeP, path: &[NodeP]) -> bool {
+        self(node, path)
+    }
+}
+
+/// Visit `root` and everything below it in document order.
+/// Nodes that are used in more than one place are visited once.
+pub fn walk<V: Visitor>(root: &NodeP, v: &mut V) {
+    let mut seen = HashSet::new();
+    let mut path: Vec<NodeP> = vec![];
+
+    // (node, depth). Nodes may be deep, avoid recursion.
+    let mut stack = vec![(root.clone(), 0)];
+
+    while let Some((node, depth)) = stack.pop() {
+        if !seen.insert(node.addr()) {
+            continue;
+        }
+        path.truncate(depth);
+
+        if v.visit(&node, &path) {
+            let mut childs = vec![];
+            node.childs(&mut childs);
+            stack.extend(childs.into_iter().rev().map(|c| (c, depth + 1)));
+            path.push(node);
+        }
+    }
+}
+
+/// all nodes below `root` (including it) that match `cond`
+pub fn find<F>(root: &NodeP, mut cond: F) -> Vec<NodeP> where F: FnMut(&NodeP) -> bool {
+    let mut out = vec![];
+    walk(root, &mut |node: &NodeP, _: &[NodeP]| {
+        if cond(node) {
+            out.push(node.clone());
+        }
+        true
+    });
+    out
+}
+
+/// blocks invoking the macro `name`, like all chapters
+pub fn blocks(root: &NodeP, name: &str) -> Vec<NodeP> {
+    find(root, |n| n.type_name() == "block" && n.name() == Some(name))
+}
+
+/// Blocks that did not find their macro.
+/// Run `Yarn::resolve` first, so late definitions are taken into account.
+pub fn unresolved(root: &NodeP) -> Vec<NodeP> {
+    find(root, |n| n.type_name() == "block" && n.target().is_none())
+}
+
+/// the words below `root`, like all words of a section
+pub fn words(root: &NodeP) -> Vec<NodeP> {
+    find(root, |n| n.type_name() == "word")
+}
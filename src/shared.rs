@@ -0,0 +1,43 @@
+//! Reference counting and interior mutability of the document graph.
+//!
+//! With the `sync` feature these are based on `Arc` and `RwLock`,
+//! so a document can be handed to other threads and laid out in parallel.
+
+#[cfg(not(feature="sync"))]
+mod imp {
+    pub use std::rc::{Rc as Shared, Weak};
+    pub use std::cell::RefCell as Lock;
+    pub use std::cell::Ref as ReadGuard;
+    
+    pub trait MaybeSync {}
+    impl<T: ?Sized> MaybeSync for T {}
+}
+
+#[cfg(feature="sync")]
+mod imp {
+    pub use std::sync::{Arc as Shared, Weak};
+    use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+    pub use std::sync::RwLockReadGuard as ReadGuard;
+    
+    pub trait MaybeSync: Send + Sync {}
+    impl<T: ?Sized + Send + Sync> MaybeSync for T {}
+    
+    /// `RwLock` with the interface of `RefCell`
+    pub struct Lock<T: ?Sized>(RwLock<T>);
+    impl<T> Lock<T> {
+        pub fn new(t: T) -> Lock<T> {
+            Lock(RwLock::new(t))
+        }
+    }
+    impl<T: ?Sized> Lock<T> {
+        // a poisoned lock means a layout thread panicked. Give up.
+        pub fn borrow(&self) -> RwLockReadGuard<T> {
+            self.0.read().expect("poisoned lock")
+        }
+        pub fn borrow_mut(&self) -> RwLockWriteGuard<T> {
+            self.0.write().expect("poisoned lock")
+        }
+    }
+}
+
+pub use self::imp::*;
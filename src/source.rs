@@ -1,7 +1,39 @@
+use std::fmt;
 use istring::IString;
 use super::LoomError;
 use io::Io;
 
+/// Where something starts in the source.
+/// The parser only knows how much input is left, `parse` fills in the rest.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Span {
+    /// byte offset
+    pub offset: usize,
+    
+    /// starting at 1, 0 if not known
+    pub line:   usize,
+    
+    /// the file it was read from, if any
+    pub file:   Option<IString>
+}
+impl Span {
+    pub fn remaining(len: usize) -> Span {
+        Span {
+            offset: len,
+            line:   0,
+            file:   None
+        }
+    }
+}
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.file {
+            Some(ref file) => write!(f, "{}:{}", file, self.line),
+            None => write!(f, "line {}", self.line)
+        }
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub enum Placeholder {
     Body,
@@ -32,7 +64,8 @@ pub enum Item {
 pub struct Parameter {
     pub name: IString,
     pub args: Vec<Item>,
-    pub value: BlockBody
+    pub value: BlockBody,
+    pub span: Span
 }
 
 #[derive(Debug, PartialEq)]
@@ -45,7 +78,8 @@ pub struct Command {
 pub struct Block {
     pub name:       IString,
     pub argument:   Vec<Item>,
-    pub body:       BlockBody
+    pub body:       BlockBody,
+    pub span:       Span
 }
 
 #[derive(Debug, PartialEq)]
@@ -63,21 +97,89 @@ pub enum Body {
     Placeholder(Placeholder)
 }
 
-pub fn parse(io: &Io, input: &str) -> Result<BlockBody, LoomError> {
+impl BlockBody {
+    // every span below, in no particular order
+    fn spans<'a>(&'a mut self, out: &mut Vec<&'a mut Span>) {
+        for p in self.parameters.iter_mut() {
+            out.push(&mut p.span);
+            p.value.spans(out);
+        }
+        for c in self.childs.iter_mut() {
+            if let Body::Block(ref mut b) = *c {
+                out.push(&mut b.span);
+                b.body.spans(out);
+            }
+        }
+    }
+    
+    // turn the remaining lengths into offsets and count the lines on the way
+    fn locate(&mut self, input: &str, file: Option<&str>) {
+        let mut spans = vec![];
+        self.spans(&mut spans);
+        for s in spans.iter_mut() {
+            s.offset = input.len() - s.offset;
+        }
+        spans.sort_by_key(|s| s.offset);
+        
+        let file = file.map(IString::from);
+        let mut line = 1;
+        let mut pos = 0;
+        for s in spans {
+            line += input[pos .. s.offset].matches('\n').count();
+            pos = s.offset;
+            s.line = line;
+            s.file = file.clone();
+        }
+    }
+}
+
+/// `file` is where `input` comes from, it ends up in the spans.
+pub fn parse(io: &Io, input: &str, file: Option<&str>) -> Result<BlockBody, LoomError> {
     use parser;
     use slug;
     
+    let text = input;
+    
     #[cfg(feature="slug")]
     let input = slug::wrap(input);
         
     match parser::block_body(input, 0) {
-        Ok((rem, out)) => {
+        Ok((rem, mut out)) => {
             if rem.len() > 0 {
                 let s: &str = rem.into();
                 warn!(io.log, "remaining:\n{}", s);
             }
+            out.locate(text, file);
             Ok(out)
         },
         Err(_) => Err(LoomError::Parser)
     }
 }
+
+#[test]
+fn test_locate() {
+    use parser;
+    use slug;
+    
+    let text = "/foo\n    x\n\n:bar\n    :baz\n        y\n:qux\n";
+    #[cfg(feature="slug")]
+    let input = slug::wrap(text);
+    #[cfg(not(feature="slug"))]
+    let input = text;
+    
+    let mut body = parser::block_body(input, 0).unwrap().1;
+    body.locate(text, Some("test.yarn"));
+    
+    let span = |offset, line| Span { offset: offset, line: line, file: Some("test.yarn".into()) };
+    assert_eq!(body.parameters[0].span, span(0, 1));
+    let (bar, qux) = match (&body.childs[0], &body.childs[1]) {
+        (&Body::Block(ref bar), &Body::Block(ref qux)) => (bar, qux),
+        _ => panic!("expected two blocks")
+    };
+    assert_eq!(bar.span, span(12, 4));
+    assert_eq!(qux.span, span(36, 7));
+    match bar.body.childs[0] {
+        Body::Block(ref baz) => assert_eq!(baz.span, span(17, 5)),
+        _ => panic!("expected a block")
+    }
+}